@@ -4,7 +4,7 @@ pub use std::time::Duration;
 pub use tokio::select;
 pub use std::sync::Arc;
 
-pub use crate::runtime::{SandBoxConfig, ContainerConfig, PodConfig, RuntimeClient};
+pub use crate::runtime::{SandBoxConfig, ContainerConfig, PodConfig, RunMode, RuntimeClient};
 
 pub type UID = String;
 pub type PodId = String;
@@ -13,7 +13,10 @@ pub type CtrId = String;
 
 #[derive(Debug, Clone)]
 pub enum Error {
-    CriError(tonic::Status)
+    CriError(tonic::Status),
+    HttpError(String),
+    IoError(String),
+    ManifestError(String),
 }
 
 impl std::fmt::Display for Error{
@@ -32,6 +35,34 @@ impl From<tonic::Status> for Error {
     }
 }
 
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Error {
+        Error::IoError(e.to_string())
+    }
+}
+
+impl From<crate::manifest::ManifestError> for Error {
+    fn from(e: crate::manifest::ManifestError) -> Error {
+        Error::ManifestError(e.to_string())
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Error {
+        Error::HttpError(e.to_string())
+    }
+}
+
+/// The name this node goes by when talking to the control plane.
+pub fn node_name() -> String {
+    match std::env::var("HYPHAE_NODE_NAME") {
+        Ok(name) => name,
+        Err(_) => std::fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|name| name.trim().to_owned())
+            .unwrap_or_default(),
+    }
+}
+
 pub fn log_err<E: std::error::Error>(e: E) {
     println!("{}", e.to_string());
 }
\ No newline at end of file
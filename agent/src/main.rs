@@ -1,7 +1,12 @@
 mod common;
+mod manifest;
+mod prober;
 mod runtime;
 mod state;
+mod static_pods;
+mod status;
 mod tasks;
+mod volumes;
 mod worktree;
 #[cfg(test)]
 mod tests;
@@ -10,20 +15,96 @@ use tokio::sync::mpsc::{ Receiver, Sender };
 use tokio::sync::watch::{Receiver as WatchRx, Sender as WatchTx};
 use tokio::task::JoinSet;
 use tokio::pin;
+use std::time::SystemTime;
 
 use common::*;
 
 const CONTAINERD_SOCKET_PATH: &'static str = "/run/containerd/containerd.sock";
 const EVENTS_BUFFER_MAX: usize = 100;
+const PROBE_RESULTS_BUFFER_MAX: usize = 100;
 const STATE_REFRESH_INTERVAL: Duration = Duration::from_millis(20_000);
 const EVENTS_RETRY_INTERVAL: Duration = Duration::from_millis(5_000);
 const EVENTS_FLUSH_INTERVAL: Duration = Duration::from_millis(4_000);
 const TARGET_REFRESH_INTERVAL: Duration = Duration::from_millis(15_000);
+const TARGET_FETCH_TIMEOUT: Duration = Duration::from_millis(10_000);
+const TARGET_URL_VAR: &'static str = "HYPHAE_TARGET_URL";
+const MANIFEST_DIR_VAR: &'static str = "HYPHAE_MANIFEST_DIR";
+const STATUS_URL_VAR: &'static str = "HYPHAE_STATUS_URL";
+const UNMANAGED_POLICY_VAR: &'static str = "HYPHAE_UNMANAGED_POLICY";
+// Stamped on everything the agent creates. Defaults to the node name, so only needs setting if
+// several agents share one containerd.
+const AGENT_ID_VAR: &'static str = "HYPHAE_AGENT_ID";
+// Where pods' volumes are kept on the node.
+const DATA_ROOT_VAR: &'static str = "HYPHAE_DATA_ROOT";
+// How long to wait, in seconds, for a container in the Unknown state to settle before forcing it
+// out, or "never".
+const UNKNOWN_CTR_TIMEOUT_VAR: &'static str = "HYPHAE_UNKNOWN_CTR_TIMEOUT";
 
-async fn poll_for_target(mut _target_tx: WatchTx<state::Target>) -> Result<(), Error> {
+async fn fetch_target(client: &reqwest::Client, url: &str) -> Result<state::Target, Error> {
+    let body = client.get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    Ok(manifest::parse_target(&body, manifest::Format::Json)?)
+}
+
+/// Publish a new target to the control loop, but only if it differs from the current one.
+/// Returns whether the target was published.
+fn publish_target(target_tx: &WatchTx<state::Target>, target: state::Target) -> bool {
+    target_tx.send_if_modified(|current| {
+        if *current == target { return false; }
+        *current = target;
+        true
+    })
+}
+
+// Periodically fetch this node's desired pods from the control plane.
+// A failed fetch keeps the last known target running rather than tearing everything down.
+async fn poll_for_target(url: String, target_tx: WatchTx<state::Target>) -> Result<(), Error> {
+    let client = reqwest::Client::builder()
+        .timeout(TARGET_FETCH_TIMEOUT)
+        .build()?;
+    let mut refresh_interval = tokio::time::interval(TARGET_REFRESH_INTERVAL);
     loop {
-        tokio::time::sleep(TARGET_REFRESH_INTERVAL).await;
+        refresh_interval.tick().await;
+        match fetch_target(&client, &url).await {
+            Ok(target) => { publish_target(&target_tx, target); }
+            Err(e) => log_err(e),
+        }
+    }
+}
+
+/// The control plane's target, plus the pods from static manifests. Those are the node's own, like the
+/// kubelet's static pods, so they win if the control plane has a pod with the same uid.
+fn merge_targets(static_pods: &state::Target, remote: &state::Target) -> state::Target {
+    let mut target = remote.clone();
+    for (uid, pod) in static_pods.pods.iter() {
+        if target.pods.contains_key(uid) {
+            println!("Pod {} has a static manifest, which takes precedence over the control plane's", uid);
+        }
+        target.pods.insert(uid.clone(), pod.clone());
+    }
+    target
+}
+
+// Publish the merged target whenever either source changes, for as long as either is around.
+async fn combine_targets(
+    mut static_rx: WatchRx<state::Target>,
+    mut remote_rx: WatchRx<state::Target>,
+    target_tx: WatchTx<state::Target>,
+) -> Result<(), Error> {
+    let (mut static_open, mut remote_open) = (true, true);
+    while static_open || remote_open {
+        select! {
+            changed = static_rx.changed(), if static_open => { static_open = changed.is_ok(); }
+            changed = remote_rx.changed(), if remote_open => { remote_open = changed.is_ok(); }
+        }
+        let target = merge_targets(&static_rx.borrow_and_update(), &remote_rx.borrow_and_update());
+        publish_target(&target_tx, target);
     }
+    Ok(())
 }
 
 // There is low hanging fruit here for improvements in managing the amount of work done by the control loop and
@@ -62,23 +143,48 @@ async fn read_events(mut rsc: RuntimeClient, ctr_events: Sender<Vec<cri::Contain
     }
 }
 
+/// Rebuild the state from scratch.
+async fn refresh_state(rsc: &mut RuntimeClient, state: &mut state::State, owned_only: bool) -> Result<(), Error> {
+    let containers = rsc.list_containers(owned_only).await?.containers;
+    let pods = rsc.list_pods(owned_only).await?.items;
+    state.ingest(containers, pods);
+    // Listing containers doesn't tell us how they exited, so ask about the ones we don't know yet.
+    for id in state.missing_exit_codes() {
+        match rsc.container_status(id.clone()).await {
+            Ok(status) => state.set_exit(&status),
+            Err(e) => log_err(e),
+        }
+    }
+    Ok(())
+}
+
 async fn control_loop(
     mut rsc: RuntimeClient,
     mut ctr_events: Receiver<Vec<cri::ContainerEventResponse>>,
-    mut new_target: WatchRx<state::Target>
-
+    mut new_target: WatchRx<state::Target>,
+    status_tx: WatchTx<status::NodeStatus>,
+    unmanaged_policy: state::UnmanagedPolicy,
+    wait_policy: worktree::WaitPolicy,
 ) -> Result<(), Error> {
+    let node = node_name();
     let mut target = state::Target::new();
-    let mut state = state::State::new();
-    let mut worktree = worktree::WorkTree::new();
-    {
-        let containers = rsc.list_containers().await?.containers;
-        let pods = rsc.list_pods().await?.items;
-        state.ingest(containers, pods);
-    }
+    let mut state = state::State::new(rsc.agent_id().to_owned(), unmanaged_policy);
+    let mut worktree = worktree::WorkTree::new(wait_policy);
+    let task_finished = worktree.finished();
+    let (probe_tx, mut probe_results) = tokio::sync::mpsc::channel(PROBE_RESULTS_BUFFER_MAX);
+    let mut prober = prober::Prober::new(probe_tx);
+    let mut projections = volumes::Projections::new();
+    // Unless we've been asked to deal with strangers, there's no need to hear about them at all.
+    let owned_only = unmanaged_policy == state::UnmanagedPolicy::Ignore;
+    refresh_state(&mut rsc, &mut state, owned_only).await?;
     let mut refresh_interval = tokio::time::interval(STATE_REFRESH_INTERVAL);
+    // When a container held back by its crash-loop backoff is next due to start.
+    let mut wake_at: Option<SystemTime> = None;
+    // Once nothing publishes targets any more, the last one stands.
+    let mut target_closed = false;
     loop {
         let mut rsc = rsc.clone();
+        let wake_in = wake_at.map(|time| time.duration_since(SystemTime::now()).unwrap_or_default());
         select! {
             events = ctr_events.recv() => {
                 let events = events.expect("Events listener suddenly exited.");
@@ -87,30 +193,93 @@ async fn control_loop(
                     state.observe(event);
                 }
             }
-            _ = new_target.changed() => {
+            changed = new_target.changed(), if !target_closed => {
+                if changed.is_err() {
+                    println!("Nothing is publishing targets any more, so the last one stands.");
+                    target_closed = true;
+                    continue;
+                }
                 target = new_target.borrow_and_update().clone();
+                state.expect(&target);
+                // Pods we don't know of may have sandboxes from before ownership labels, which only
+                // listing everything finds.
+                if target.pods.keys().any(|uid| !state.pods.contains_key(uid)) {
+                    refresh_state(&mut rsc, &mut state, false).await?;
+                }
+            }
+            result = probe_results.recv() => {
+                state.record_probe(result.expect("The prober holds a sender."));
             }
             _ = refresh_interval.tick() => {
-                let containers = rsc.list_containers().await?.containers;
-                let pods = rsc.list_pods().await?.items;
-                state.ingest(containers, pods);
+                let owned_only = owned_only && !state.has_unlabelled();
+                refresh_state(&mut rsc, &mut state, owned_only).await?;
             }
+            _ = tokio::time::sleep(wake_in.unwrap_or_default()), if wake_in.is_some() => {}
+            // Something we were doing is done (or given up on), so there may be more to do. What it
+            // did may not have shown up as an event yet, so look for ourselves.
+            _ = task_finished.notified() => {
+                let owned_only = owned_only && !state.has_unlabelled();
+                refresh_state(&mut rsc, &mut state, owned_only).await?;
+            }
+        }
+        for (id, resized) in worktree.take_resizes() {
+            state.record_resize(id, resized);
         }
-        let plan = state::diff(&target, &state);
+        state.prune(&target);
+        state.update_readiness(&target, &worktree.post_starting());
+        worktree.prune_hook_errors(&state);
+        // Before planning, so that a pod's files are there by the time its containers are created.
+        projections.sync(rsc.data_root(), &state, &target).await;
+        let plan = state::diff(&target, &state, SystemTime::now());
+        wake_at = plan.wake_at;
         dbg!(&plan);
         worktree = worktree::execute(plan, worktree, &mut rsc);
+        prober.sync(&state, &target, &rsc);
+
+        let status = status::NodeStatus::snapshot(node.clone(), &state, &target, &worktree);
+        status_tx.send_if_modified(|current| {
+            if *current == status { return false; }
+            *current = status;
+            true
+        });
     }
 }
 
 async fn agent() {
-    let runtime = RuntimeClient::connect().await.expect("Could not connect to containerd.");
+    let agent_id = std::env::var(AGENT_ID_VAR).unwrap_or_else(|_| node_name());
+    let data_root = std::env::var(DATA_ROOT_VAR).unwrap_or(volumes::DEFAULT_DATA_ROOT.to_owned());
+    let runtime = RuntimeClient::connect(agent_id, data_root.into()).await.expect("Could not connect to containerd.");
     let mut set = JoinSet::new();
     let (events_tx, events_rx) = tokio::sync::mpsc::channel(EVENTS_BUFFER_MAX);
     let (target_tx, target_rx) = tokio::sync::watch::channel(state::Target::new());
+    let (status_tx, status_rx) = tokio::sync::watch::channel(status::NodeStatus::new(node_name()));
+    let unmanaged_policy = match std::env::var(UNMANAGED_POLICY_VAR) {
+        Ok(policy) => policy.parse().expect("Invalid HYPHAE_UNMANAGED_POLICY."),
+        Err(_) => state::UnmanagedPolicy::Ignore,
+    };
+    let wait_policy = match std::env::var(UNKNOWN_CTR_TIMEOUT_VAR) {
+        Ok(policy) => policy.parse().expect("Invalid HYPHAE_UNKNOWN_CTR_TIMEOUT."),
+        Err(_) => worktree::WaitPolicy::default(),
+    };
 
-    set.spawn(poll_for_target(target_tx));
+    // Pods come from static manifests, and from the control plane if one is configured. Static pods
+    // run either way, so that they're up before there's a control plane and while it's unreachable.
+    let (static_tx, static_rx) = tokio::sync::watch::channel(state::Target::new());
+    let (remote_tx, remote_rx) = tokio::sync::watch::channel(state::Target::new());
+    let dir = std::env::var(MANIFEST_DIR_VAR)
+        .unwrap_or(static_pods::DEFAULT_MANIFEST_DIR.to_owned());
+    set.spawn(static_pods::watch_manifests(dir.into(), static_tx));
+    if let Ok(url) = std::env::var(TARGET_URL_VAR) {
+        set.spawn(poll_for_target(url, remote_tx));
+    } else {
+        drop(remote_tx);
+    }
+    set.spawn(combine_targets(static_rx, remote_rx, target_tx));
+    if let Ok(url) = std::env::var(STATUS_URL_VAR) {
+        set.spawn(status::report_status(url, status_rx));
+    }
     set.spawn(read_events(runtime.clone(), events_tx));
-    set.spawn(control_loop(runtime.clone(), events_rx, target_rx));
+    set.spawn(control_loop(runtime.clone(), events_rx, target_rx, status_tx, unmanaged_policy, wait_policy));
 
     let results = set.join_all().await;
     for result in results {
@@ -0,0 +1,805 @@
+//! The manifest format for describing pods, shared by static pod files and the control plane.
+//!
+//! A static pod manifest describes one pod:
+//!
+//! ```toml
+//! api_version = "hyphae/v1"
+//! restart_policy = "on-failure"  # default: "always". Or "never"
+//!
+//! [sandbox]
+//! name = "log-shipper"        # required
+//! uid = "7f0c7a54"            # required, unique on the node
+//! namespace = "kube-system"   # default: "default"
+//! generation = 3              # default: 0. Bump it whenever the spec changes
+//! volumes = [                 # default: []. Created along with the sandbox, and removed with it
+//!     { name = "buffer", type = "empty_dir" },  # an empty directory on the node's disk
+//!     { name = "scratch", type = "tmpfs", size_mb = 64 },  # in memory. size_mb default: half the node's memory
+//!     { name = "logs", type = "host_path", path = "/var/log", read_only = true },  # read_only default: false
+//!     # The files of one of the node's configs or secrets, read-only. Updated in place when they change.
+//!     # Secrets are kept in memory, and only readable by root. Only the control plane can provide these
+//!     { name = "settings", type = "config", config = "vector-settings" },
+//!     { name = "api-key", type = "secret", secret = "datadog" },
+//! ]
+//!
+//! [containers.vector]         # the key is the container's name
+//! image = "docker.io/timberio/vector:latest"  # required
+//! command = "/usr/bin/vector" # default: the image's entrypoint
+//! args = ["--config", "/etc/vector/vector.toml"]  # default: []
+//! working_dir = "/"           # default: the image's working directory
+//! privileged = false          # default: false
+//! run_mode = "service"        # default: "service". A "job" is run until it exits 0
+//! sidecar = false             # default: false. Stopped once the pod's other containers have all succeeded
+//! backoff_limit = 6           # default: 6. How many times a failed job is retried
+//! grace_period = 30           # default: 30. Seconds to exit after being asked to stop
+//! stop_signal = "SIGQUIT"     # default: the image's stop signal
+//! depends_on = ["proxy"]      # default: []. Started once these are ready, and stopped before them
+//! # Restart the container if its check fails `failure_threshold` times in a row. Checks are like
+//! # hooks (below), or { type = "tcp_socket", port = 8686 }. Default: no probe
+//! liveness_probe = { check = { type = "http_get", path = "/health", port = 8686 }, initial_delay = 0, period = 10, timeout = 1, failure_threshold = 3 }
+//! # Like the liveness probe, but only marks the container unready while it fails. Default: ready while running
+//! readiness_probe = { check = { type = "tcp_socket", port = 8686 } }
+//! # Holds off the other probes until it passes, and restarts the container if it fails. Default: no probe
+//! startup_probe = { check = { type = "tcp_socket", port = 8686 }, failure_threshold = 30 }
+//! envs = [                    # default: []
+//!     { name = "VECTOR_LOG", value = "info" },
+//!     # Or looked up when the container is created. Configs and secrets only come from the control plane
+//!     { name = "DD_API_KEY", value_from = { type = "secret", secret = "datadog", key = "api-key" } },
+//!     { name = "SINK", value_from = { type = "config", config = "vector-settings", key = "sink" } },
+//!     # One of "pod_uid", "pod_name", "namespace", "node_name" or "pod_ip"
+//!     { name = "HOSTNAME", value_from = { type = "field", field = "node_name" } },
+//!     # "cpu_millis" or "memory_mb". The container has to have that limit
+//!     { name = "MEMORY_MB", value_from = { type = "resource", resource = "memory_mb" } },
+//! ]
+//! volume_mounts = [           # default: []. Mounts the pod's volumes into the container
+//!     { name = "buffer", mount_path = "/var/lib/vector" },
+//!     { name = "logs", mount_path = "/var/log", read_only = true },  # read_only default: false
+//! ]
+//!
+//! [containers.vector.lifecycle]  # default: no hooks
+//! # Run after the container starts. If it fails, the container is killed
+//! post_start = { type = "exec", command = ["/bin/sh", "-c", "touch /tmp/started"] }
+//! # Run before the container is stopped, within its grace period
+//! pre_stop = { type = "http_get", path = "/drain", port = 8686 }  # on the pod's IP
+//!
+//! # Default: no limits. The pod as a whole is limited to the sum of its containers' limits, or
+//! # to the largest init container's if that's more, as long as every container has that limit,
+//! # and is recreated if its containers come to need more than that, or if one of them drops a
+//! # limit, since that leaves the pod without it.
+//! # Changes are made to running containers in place where the runtime allows, except for removing
+//! # a limit or changing one an env var comes from, which recreate the container. Raising one past
+//! # what the pod was given only recreates the pod if the runtime refuses to change the container
+//! [containers.vector.resources]
+//! cpu_shares = 512            # its weight when CPUs are contended. Default: the runtime's (1024)
+//! cpu_millis = 1500           # CPU time, in thousandths of a CPU
+//! cpuset_cpus = "0-3"         # which CPUs it may run on
+//! cpuset_mems = "0"           # which memory nodes it may use
+//! memory_mb = 512
+//! memory_swap_mb = 1024       # memory and swap together, at least memory_mb
+//! pids = 256                  # processes and threads
+//! hugepages_mb = { "2MB" = 64 }  # by page size
+//!
+//! # Run one at a time, in order, each until it exits 0, before the containers are created, and
+//! # again whenever the sandbox is recreated. Default: none. Takes the same fields as a container,
+//! # except for probes, run_mode and sidecar
+//! [[init_containers]]
+//! name = "fetch-config"       # required, and unique among the pod's containers
+//! image = "docker.io/library/busybox:latest"
+//! command = "/bin/wget"
+//! args = ["-O", "/etc/vector/vector.toml", "http://config.internal/vector.toml"]
+//! ```
+//!
+//! The control plane serves every pod for a node at once, as a list of pods under `pods`:
+//!
+//! ```json
+//! { "api_version": "hyphae/v1", "pods": [ { "sandbox": { ... }, "containers": { ... } } ] }
+//! ```
+//!
+//! along with the files of the configs and secrets that those pods' volumes use, by name:
+//!
+//! ```json
+//! { "configs": { "vector-settings": { "vector.toml": "..." } }, "secrets": { "datadog": { "api-key": "..." } } }
+//! ```
+//!
+//! Manifests may be written as JSON, TOML or YAML. Unknown fields are rejected, and every error
+//! names the field it is about, e.g. `containers.vector.image: must not be empty`.
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use crate::common::*;
+use crate::runtime::{Check, EnvSource, Probe, Resources, RestartPolicy, Volume};
+use crate::state::{Files, Target};
+
+pub const API_VERSION: &'static str = "hyphae/v1";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl Format {
+    /// Guess the format of a manifest file from its extension.
+    pub fn from_path(path: &Path) -> Option<Format> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => Some(Format::Json),
+            Some("toml") => Some(Format::Toml),
+            Some("yaml") | Some("yml") => Some(Format::Yaml),
+            _ => None,
+        }
+    }
+}
+
+/// A problem with a manifest, and the field it was found at.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ManifestError {
+    pub field: String,
+    pub message: String,
+}
+
+impl ManifestError {
+    fn new(field: impl Into<String>, message: impl Into<String>) -> ManifestError {
+        ManifestError { field: field.into(), message: message.into() }
+    }
+}
+
+impl std::fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+impl std::error::Error for ManifestError {
+
+}
+
+/// A single pod. This is what a static pod manifest file contains.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PodManifest {
+    pub api_version: String,
+    #[serde(default)]
+    pub restart_policy: RestartPolicy,
+    pub sandbox: SandBoxConfig,
+    pub containers: HashMap<Name, ContainerConfig>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub init_containers: Vec<ContainerConfig>,
+}
+
+/// Every pod a node should be running. This is what the control plane serves.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TargetManifest {
+    pub api_version: String,
+    #[serde(default)]
+    pub pods: Vec<PodConfig>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub configs: HashMap<Name, Files>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub secrets: HashMap<Name, Files>,
+}
+
+impl From<PodConfig> for PodManifest {
+    fn from(pod: PodConfig) -> PodManifest {
+        PodManifest {
+            api_version: API_VERSION.to_owned(),
+            restart_policy: pod.restart_policy,
+            sandbox: pod.config,
+            containers: pod.containers,
+            init_containers: pod.init_containers,
+        }
+    }
+}
+
+impl From<&Target> for TargetManifest {
+    fn from(target: &Target) -> TargetManifest {
+        let mut pods: Vec<PodConfig> = target.pods.values().cloned().collect();
+        pods.sort_by(|a, b| a.config.uid.cmp(&b.config.uid));
+        TargetManifest {
+            api_version: API_VERSION.to_owned(),
+            pods,
+            configs: target.configs.clone(),
+            secrets: target.secrets.clone(),
+        }
+    }
+}
+
+fn deserialize<T: for<'de> Deserialize<'de>>(contents: &str, format: Format) -> Result<T, ManifestError> {
+    fn path_error<E: std::fmt::Display>(e: serde_path_to_error::Error<E>) -> ManifestError {
+        ManifestError::new(e.path().to_string(), e.inner().to_string())
+    }
+    match format {
+        Format::Json => {
+            let mut de = serde_json::Deserializer::from_str(contents);
+            serde_path_to_error::deserialize(&mut de).map_err(path_error)
+        }
+        Format::Toml => {
+            let de = toml::Deserializer::parse(contents)
+                .map_err(|e| ManifestError::new(".", e.to_string()))?;
+            serde_path_to_error::deserialize(de).map_err(path_error)
+        }
+        Format::Yaml => {
+            let de = serde_yaml::Deserializer::from_str(contents);
+            serde_path_to_error::deserialize(de).map_err(path_error)
+        }
+    }
+}
+
+/// Parse and validate a single pod manifest.
+pub fn parse_pod(contents: &str, format: Format) -> Result<PodConfig, ManifestError> {
+    let manifest: PodManifest = deserialize(contents, format)?;
+    check_version(&manifest.api_version, "api_version")?;
+    let mut pod = PodConfig {
+        restart_policy: manifest.restart_policy,
+        config: manifest.sandbox,
+        containers: manifest.containers,
+        init_containers: manifest.init_containers,
+    };
+    validate_pod(&mut pod, "", &Target::new())?;
+    Ok(pod)
+}
+
+/// Parse and validate the full set of pods for a node.
+pub fn parse_target(contents: &str, format: Format) -> Result<Target, ManifestError> {
+    let manifest: TargetManifest = deserialize(contents, format)?;
+    check_version(&manifest.api_version, "api_version")?;
+    let mut target = Target::new();
+    for (kind, sets) in [("configs", &manifest.configs), ("secrets", &manifest.secrets)] {
+        for (name, files) in sets.iter() {
+            let field = format!("{}.{}", kind, name);
+            check_name(name, &field)?;
+            for file in files.keys() {
+                // Names starting with ".." are where the files really live. See volumes.rs.
+                if file.is_empty() || file.contains('/') || file.starts_with("..") {
+                    return Err(ManifestError::new(
+                        format!("{}.{}", field, file),
+                        "must be non-empty, must not contain '/' and must not start with '..'"
+                    ));
+                }
+            }
+        }
+    }
+    target.configs = manifest.configs;
+    target.secrets = manifest.secrets;
+    for (i, mut pod) in manifest.pods.into_iter().enumerate() {
+        let prefix = format!("pods[{}].", i);
+        validate_pod(&mut pod, &prefix, &target)?;
+        let uid = pod.config.uid.clone();
+        if target.pods.contains_key(&uid) {
+            return Err(ManifestError::new(prefix + "sandbox.uid", format!("duplicate pod uid {}", uid)));
+        }
+        target.pods.insert(uid, pod);
+    }
+    Ok(target)
+}
+
+fn check_version(version: &str, field: &str) -> Result<(), ManifestError> {
+    if version != API_VERSION {
+        return Err(ManifestError::new(field, format!("unsupported version {:?}, expected {:?}", version, API_VERSION)));
+    }
+    Ok(())
+}
+
+// Names end up in paths and CRI metadata, so hold them to the rules for DNS labels.
+fn check_name(name: &str, field: &str) -> Result<(), ManifestError> {
+    if name.is_empty() {
+        return Err(ManifestError::new(field, "must not be empty"));
+    }
+    let valid = name.len() <= 63
+        && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        && !name.starts_with('-')
+        && !name.ends_with('-');
+    if !valid {
+        return Err(ManifestError::new(
+            field,
+            format!("{:?} must be at most 63 lowercase letters, digits or '-', and start and end with a letter or digit", name)
+        ));
+    }
+    Ok(())
+}
+
+// Hooks and probes' checks.
+fn check_check(check: &Check, field: &str) -> Result<(), ManifestError> {
+    match check {
+        Check::Exec { command } if command.is_empty() => {
+            Err(ManifestError::new(format!("{}.command", field), "must not be empty"))
+        }
+        Check::HttpGet { path, .. } if !path.starts_with('/') => {
+            Err(ManifestError::new(format!("{}.path", field), format!("{:?} must start with '/'", path)))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn check_probe(probe: &Probe, field: &str) -> Result<(), ManifestError> {
+    check_check(&probe.check, &format!("{}.check", field))?;
+    for (name, value) in [("period", probe.period), ("timeout", probe.timeout), ("failure_threshold", probe.failure_threshold as u64)] {
+        if value == 0 {
+            return Err(ManifestError::new(format!("{}.{}", field, name), "must be at least 1"));
+        }
+    }
+    Ok(())
+}
+
+fn check_resources(resources: &Resources, field: &str) -> Result<(), ManifestError> {
+    if let Some(shares) = resources.cpu_shares {
+        if !(2..=262144).contains(&shares) {
+            return Err(ManifestError::new(format!("{}.cpu_shares", field), "must be between 2 and 262144"));
+        }
+    }
+    for (name, value) in [("cpu_millis", resources.cpu_millis), ("memory_mb", resources.memory_mb), ("pids", resources.pids)] {
+        if value == Some(0) {
+            return Err(ManifestError::new(format!("{}.{}", field, name), "must be at least 1"));
+        }
+    }
+    if let Some(swap) = resources.memory_swap_mb {
+        if resources.memory_mb.map_or(true, |memory| swap < memory) {
+            return Err(ManifestError::new(format!("{}.memory_swap_mb", field), "must be at least memory_mb, which has to be set too"));
+        }
+    }
+    for (name, cpuset) in [("cpuset_cpus", &resources.cpuset_cpus), ("cpuset_mems", &resources.cpuset_mems)] {
+        if let Some(cpuset) = cpuset {
+            if cpuset.is_empty() || !cpuset.chars().all(|c| c.is_ascii_digit() || c == ',' || c == '-') {
+                return Err(ManifestError::new(format!("{}.{}", field, name), format!("{:?} is not a list of numbers and ranges, e.g. \"0-3,6\"", cpuset)));
+            }
+        }
+    }
+    for page_size in resources.hugepages_mb.keys() {
+        let number = page_size.strip_suffix("KB").or(page_size.strip_suffix("MB")).or(page_size.strip_suffix("GB"));
+        if !number.map_or(false, |number| !number.is_empty() && number.chars().all(|c| c.is_ascii_digit())) {
+            return Err(ManifestError::new(format!("{}.hugepages_mb", field), format!("{:?} is not a page size, e.g. \"2MB\"", page_size)));
+        }
+    }
+    Ok(())
+}
+
+fn check_signal(signal: &str, field: &str) -> Result<(), ManifestError> {
+    let name = signal.strip_prefix("SIG").unwrap_or("");
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit()) {
+        return Err(ManifestError::new(field, format!("{:?} is not a signal name, e.g. SIGTERM", signal)));
+    }
+    Ok(())
+}
+
+/// Check a pod for problems that serde can't catch, and fill in defaults that depend on other fields.
+/// The configs and secrets its volumes and env vars refer to have to be in `target`.
+fn validate_pod(pod: &mut PodConfig, prefix: &str, target: &Target) -> Result<(), ManifestError> {
+    let sandbox = &pod.config;
+    check_name(&sandbox.name, &format!("{}sandbox.name", prefix))?;
+    check_name(&sandbox.namespace, &format!("{}sandbox.namespace", prefix))?;
+    if sandbox.uid.is_empty() || sandbox.uid.contains('/') {
+        return Err(ManifestError::new(format!("{}sandbox.uid", prefix), "must be non-empty and must not contain '/'"));
+    }
+    for (i, volume) in sandbox.volumes.iter().enumerate() {
+        let field = format!("{}sandbox.volumes[{}]", prefix, i);
+        check_name(volume.name(), &(field.clone() + ".name"))?;
+        if sandbox.volumes[..i].iter().any(|other| other.name() == volume.name()) {
+            return Err(ManifestError::new(field + ".name", format!("{:?} is already taken by another volume", volume.name())));
+        }
+        match volume {
+            Volume::Tmpfs { size_mb: Some(0), .. } => {
+                return Err(ManifestError::new(field + ".size_mb", "must be at least 1"));
+            }
+            Volume::HostPath { path, .. } if !path.starts_with('/') => {
+                return Err(ManifestError::new(field + ".path", format!("{:?} must be an absolute path", path)));
+            }
+            Volume::Config { config, .. } if !target.configs.contains_key(config) => {
+                return Err(ManifestError::new(field + ".config", format!("{:?} is not one of the node's configs", config)));
+            }
+            Volume::Secret { secret, .. } if !target.secrets.contains_key(secret) => {
+                return Err(ManifestError::new(field + ".secret", format!("{:?} is not one of the node's secrets", secret)));
+            }
+            _ => {}
+        }
+    }
+    if pod.containers.is_empty() {
+        return Err(ManifestError::new(format!("{}containers", prefix), "a pod needs at least one container"));
+    }
+    if pod.containers.values().all(|ctr| ctr.sidecar) {
+        return Err(ManifestError::new(format!("{}containers", prefix), "a pod needs at least one container that isn't a sidecar"));
+    }
+
+    for (key, ctr) in pod.containers.iter_mut() {
+        let field = format!("{}containers.{}", prefix, key);
+        check_name(key, &field)?;
+        if ctr.name.is_empty() {
+            ctr.name = key.clone();
+        } else if &ctr.name != key {
+            return Err(ManifestError::new(field + ".name", format!("{:?} does not match the container's key {:?}", ctr.name, key)));
+        }
+        validate_container(ctr, &field, &sandbox.volumes, target)?;
+    }
+    check_dependencies(&pod.containers, prefix)?;
+
+    for (i, ctr) in pod.init_containers.iter().enumerate() {
+        let field = format!("{}init_containers[{}]", prefix, i);
+        check_name(&ctr.name, &(field.clone() + ".name"))?;
+        let taken = pod.containers.contains_key(&ctr.name)
+            || pod.init_containers[..i].iter().any(|other| other.name == ctr.name);
+        if taken {
+            return Err(ManifestError::new(field + ".name", format!("{:?} is already taken by another container", ctr.name)));
+        }
+        // They run to completion by definition, and nothing waits for them to be ready.
+        if ctr.run_mode != RunMode::Service || ctr.sidecar {
+            return Err(ManifestError::new(field, "init containers always run to completion, and can't be Jobs or sidecars"));
+        }
+        if ctr.liveness_probe.is_some() || ctr.readiness_probe.is_some() || ctr.startup_probe.is_some() {
+            return Err(ManifestError::new(field, "init containers can't have probes"));
+        }
+        validate_container(ctr, &field, &sandbox.volumes, target)?;
+    }
+    Ok(())
+}
+
+// Every dependency has to be another of the pod's containers, and there can't be any cycles.
+fn check_dependencies(containers: &HashMap<Name, ContainerConfig>, prefix: &str) -> Result<(), ManifestError> {
+    for (name, ctr) in containers.iter() {
+        let field = format!("{}containers.{}.depends_on", prefix, name);
+        for dep in ctr.depends_on.iter() {
+            if dep == name || !containers.contains_key(dep) {
+                return Err(ManifestError::new(field, format!("{:?} is not another container in the pod", dep)));
+            }
+        }
+        // Walk everything it depends on, directly or not, looking for itself.
+        let mut seen = std::collections::HashSet::new();
+        let mut todo: Vec<&Name> = ctr.depends_on.iter().collect();
+        while let Some(dep) = todo.pop() {
+            if dep == name {
+                return Err(ManifestError::new(field, "depends on itself through its dependencies"));
+            }
+            if seen.insert(dep) {
+                todo.extend(containers[dep].depends_on.iter());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks shared by containers and init containers.
+fn validate_container(ctr: &ContainerConfig, field: &str, volumes: &[Volume], target: &Target) -> Result<(), ManifestError> {
+    let field = field.to_owned();
+    if ctr.image.is_empty() {
+        return Err(ManifestError::new(field + ".image", "must not be empty"));
+    }
+    if let Some(ref signal) = ctr.stop_signal {
+        check_signal(signal, &(field.clone() + ".stop_signal"))?;
+    }
+    if let Some(ref hook) = ctr.lifecycle.post_start {
+        check_check(&hook.clone().into(), &(field.clone() + ".lifecycle.post_start"))?;
+    }
+    if let Some(ref hook) = ctr.lifecycle.pre_stop {
+        check_check(&hook.clone().into(), &(field.clone() + ".lifecycle.pre_stop"))?;
+    }
+    check_resources(&ctr.resources, &(field.clone() + ".resources"))?;
+    let probes = [("liveness_probe", &ctr.liveness_probe), ("readiness_probe", &ctr.readiness_probe), ("startup_probe", &ctr.startup_probe)];
+    for (name, probe) in probes {
+        if let Some(probe) = probe {
+            check_probe(probe, &format!("{}.{}", field, name))?;
+        }
+    }
+    for (i, env) in ctr.envs.iter().enumerate() {
+        let field = format!("{}.envs[{}]", field, i);
+        if env.name.is_empty() || env.name.contains('=') {
+            return Err(ManifestError::new(field + ".name", "must be non-empty and must not contain '='"));
+        }
+        let found = match env.value_from {
+            None => { continue; }
+            Some(_) if !env.value.is_empty() => {
+                return Err(ManifestError::new(field, "can't have both a value and value_from"));
+            }
+            Some(EnvSource::Config { ref config, ref key }) => target.configs.get(config).map_or(false, |files| files.contains_key(key)),
+            Some(EnvSource::Secret { ref secret, ref key }) => target.secrets.get(secret).map_or(false, |files| files.contains_key(key)),
+            Some(EnvSource::Resource { resource }) if resource.limit(&ctr.resources).is_none() => {
+                return Err(ManifestError::new(field + ".value_from", "refers to a limit the container doesn't have"));
+            }
+            Some(EnvSource::Field { .. }) | Some(EnvSource::Resource { .. }) => true,
+        };
+        if !found {
+            return Err(ManifestError::new(field + ".value_from", "refers to a config or secret key the node doesn't have"));
+        }
+    }
+    for (i, mount) in ctr.volume_mounts.iter().enumerate() {
+        let field = format!("{}.volume_mounts[{}]", field, i);
+        if !volumes.iter().any(|volume| volume.name() == mount.name) {
+            return Err(ManifestError::new(field + ".name", format!("{:?} is not one of the pod's volumes", mount.name)));
+        }
+        if !mount.mount_path.starts_with('/') {
+            return Err(ManifestError::new(field + ".mount_path", format!("{:?} must be an absolute path", mount.mount_path)));
+        }
+        if ctr.volume_mounts[..i].iter().any(|other| other.mount_path == mount.mount_path) {
+            return Err(ManifestError::new(field + ".mount_path", format!("{:?} is already taken by another mount", mount.mount_path)));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::{EnvVar, Hook, PodField};
+
+    const SHIPPER_TOML: &'static str = r#"
+api_version = "hyphae/v1"
+
+[sandbox]
+name = "log-shipper"
+uid = "uid-shipper"
+
+[containers.vector]
+image = "docker.io/timberio/vector:latest"
+args = ["--config", "/etc/vector/vector.toml"]
+envs = [
+    { name = "VECTOR_LOG", value = "info" },
+    { name = "VECTOR_THREADS", value = "2" },
+]
+"#;
+
+    fn serialize<T: Serialize>(value: &T, format: Format) -> String {
+        match format {
+            Format::Json => serde_json::to_string_pretty(value).unwrap(),
+            Format::Toml => toml::to_string(value).unwrap(),
+            Format::Yaml => serde_yaml::to_string(value).unwrap(),
+        }
+    }
+
+    fn render_pod(pod: &PodConfig, format: Format) -> String {
+        serialize(&PodManifest::from(pod.clone()), format)
+    }
+
+    fn render_target(target: &Target, format: Format) -> String {
+        serialize(&TargetManifest::from(target), format)
+    }
+
+    fn shipper() -> PodConfig {
+        parse_pod(SHIPPER_TOML, Format::Toml).unwrap()
+    }
+
+    #[test]
+    fn defaults_are_filled_in() {
+        let pod = shipper();
+        assert_eq!(pod.config.namespace, "default");
+        let vector = &pod.containers["vector"];
+        assert_eq!(vector.name, "vector");
+        assert_eq!(vector.command, "");
+        assert_eq!(vector.working_dir, "");
+        assert!(!vector.privileged);
+        assert_eq!((vector.grace_period, vector.stop_signal.as_deref()), (30, None));
+        assert_eq!(vector.envs, vec![EnvVar::new("VECTOR_LOG", "info"), EnvVar::new("VECTOR_THREADS", "2")]);
+    }
+
+    #[test]
+    fn env_sources_are_checked() {
+        let with_ip = SHIPPER_TOML.replace(
+            r#"{ name = "VECTOR_THREADS", value = "2" },"#,
+            r#"{ name = "POD_IP", value_from = { type = "field", field = "pod_ip" } },"#,
+        );
+        let pod = parse_pod(&with_ip, Format::Toml).unwrap();
+        assert_eq!(pod.containers["vector"].envs[1].value_from, Some(EnvSource::Field { field: PodField::PodIp }));
+        for format in [Format::Json, Format::Toml, Format::Yaml] {
+            assert_eq!(parse_pod(&render_pod(&pod, format), format).unwrap(), pod, "{:?}", format);
+        }
+
+        let both = with_ip.replace("{ name = \"POD_IP\",", "{ name = \"POD_IP\", value = \"1\",");
+        assert_eq!(error_field(&both, Format::Toml), "containers.vector.envs[1]");
+        let secret = with_ip.replace(r#"{ type = "field", field = "pod_ip" }"#, r#"{ type = "secret", secret = "datadog", key = "api-key" }"#);
+        assert_eq!(error_field(&secret, Format::Toml), "containers.vector.envs[1].value_from");
+    }
+
+    #[test]
+    fn resources_are_checked() {
+        let toml = format!("{}{}", SHIPPER_TOML, r#"
+[containers.vector.resources]
+cpu_millis = 1500
+memory_mb = 512
+memory_swap_mb = 1024
+hugepages_mb = { "2MB" = 64 }
+"#);
+        let pod = parse_pod(&toml, Format::Toml).unwrap();
+        assert_eq!(pod.containers["vector"].resources.hugepages_mb["2MB"], 64);
+        for format in [Format::Json, Format::Toml, Format::Yaml] {
+            assert_eq!(parse_pod(&render_pod(&pod, format), format).unwrap(), pod, "{:?}", format);
+        }
+
+        let no_swap = toml.replace("1024", "256");
+        assert_eq!(error_field(&no_swap, Format::Toml), "containers.vector.resources.memory_swap_mb");
+        let page_size = toml.replace("\"2MB\"", "\"2M\"");
+        assert_eq!(error_field(&page_size, Format::Toml), "containers.vector.resources.hugepages_mb");
+        let unlimited = toml.replace("memory_mb = 512\nmemory_swap_mb = 1024\n", "").replace(
+            r#"{ name = "VECTOR_THREADS", value = "2" },"#,
+            r#"{ name = "MEMORY_MB", value_from = { type = "resource", resource = "memory_mb" } },"#,
+        );
+        assert_eq!(error_field(&unlimited, Format::Toml), "containers.vector.envs[1].value_from");
+    }
+
+    #[test]
+    fn hooks_are_parsed() {
+        let yaml = r#"
+api_version: hyphae/v1
+sandbox: { name: web, uid: uid-web }
+containers:
+  nginx:
+    image: docker.io/library/nginx:latest
+    lifecycle:
+      post_start: { type: exec, command: ["/bin/sh", "-c", "touch /tmp/started"] }
+      pre_stop: { type: http_get, path: /drain, port: 8080 }
+"#;
+        let pod = parse_pod(yaml, Format::Yaml).unwrap();
+        let lifecycle = &pod.containers["nginx"].lifecycle;
+        assert!(matches!(lifecycle.post_start, Some(Hook::Exec { ref command }) if command.len() == 3));
+        assert_eq!(lifecycle.pre_stop, Some(Hook::HttpGet { path: "/drain".to_owned(), port: 8080 }));
+        for format in [Format::Json, Format::Toml, Format::Yaml] {
+            assert_eq!(parse_pod(&render_pod(&pod, format), format).unwrap(), pod, "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn init_containers_keep_their_order() {
+        let toml = format!("{}{}", SHIPPER_TOML, r#"
+[[init_containers]]
+name = "fetch-config"
+image = "docker.io/library/busybox:latest"
+
+[[init_containers]]
+name = "check-config"
+image = "docker.io/timberio/vector:latest"
+args = ["validate"]
+"#);
+        let pod = parse_pod(&toml, Format::Toml).unwrap();
+        let names: Vec<&str> = pod.init_containers.iter().map(|ctr| ctr.name.as_str()).collect();
+        assert_eq!(names, vec!["fetch-config", "check-config"]);
+        assert_eq!(pod.container("check-config").map(|ctr| ctr.args.len()), Some(1));
+        for format in [Format::Json, Format::Toml, Format::Yaml] {
+            assert_eq!(parse_pod(&render_pod(&pod, format), format).unwrap(), pod, "{:?}", format);
+        }
+
+        let taken = toml.replace("fetch-config", "vector");
+        assert_eq!(error_field(&taken, Format::Toml), "init_containers[0].name");
+    }
+
+    #[test]
+    fn volumes_are_parsed() {
+        let toml = SHIPPER_TOML
+            .replace("[sandbox]\n", r#"[sandbox]
+volumes = [
+    { name = "buffer", type = "empty_dir" },
+    { name = "scratch", type = "tmpfs", size_mb = 64 },
+    { name = "logs", type = "host_path", path = "/var/log", read_only = true },
+]
+"#)
+            .replace("args = [", r#"volume_mounts = [{ name = "logs", mount_path = "/var/log" }]
+args = ["#);
+        let pod = parse_pod(&toml, Format::Toml).unwrap();
+        assert_eq!(pod.config.volumes[1], Volume::Tmpfs { name: "scratch".to_owned(), size_mb: Some(64) });
+        assert_eq!(pod.containers["vector"].volume_mounts[0].name, "logs");
+        for format in [Format::Json, Format::Toml, Format::Yaml] {
+            assert_eq!(parse_pod(&render_pod(&pod, format), format).unwrap(), pod, "{:?}", format);
+        }
+
+        let unknown = toml.replace("name = \"logs\", mount_path", "name = \"log\", mount_path");
+        assert_eq!(error_field(&unknown, Format::Toml), "containers.vector.volume_mounts[0].name");
+        let duplicate = toml.replace("\"scratch\"", "\"buffer\"");
+        assert_eq!(error_field(&duplicate, Format::Toml), "sandbox.volumes[1].name");
+        let relative = toml.replace("path = \"/var/log\", read_only", "path = \"var/log\", read_only");
+        assert_eq!(error_field(&relative, Format::Toml), "sandbox.volumes[2].path");
+    }
+
+    #[test]
+    fn pods_round_trip() {
+        let pod = shipper();
+        for format in [Format::Json, Format::Toml, Format::Yaml] {
+            let rendered = render_pod(&pod, format);
+            assert_eq!(parse_pod(&rendered, format).unwrap(), pod, "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn targets_round_trip() {
+        let mut target = Target::new();
+        let pod = shipper();
+        target.pods.insert(pod.config.uid.clone(), pod.clone());
+        let mut other = pod;
+        other.config.uid = "uid-other".to_owned();
+        target.pods.insert(other.config.uid.clone(), other);
+        for format in [Format::Json, Format::Toml, Format::Yaml] {
+            let rendered = render_target(&target, format);
+            assert_eq!(parse_target(&rendered, format).unwrap(), target, "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn config_volumes_need_their_config() {
+        let mut target = Target::new();
+        let mut pod = shipper();
+        pod.config.volumes = vec![
+            Volume::Config { name: "settings".to_owned(), config: "vector-settings".to_owned() },
+            Volume::Secret { name: "api-key".to_owned(), secret: "datadog".to_owned() },
+        ];
+        target.pods.insert(pod.config.uid.clone(), pod);
+        target.configs.insert("vector-settings".to_owned(), Files::from([("vector.toml".to_owned(), "[sources]".to_owned())]));
+        let rendered = render_target(&target, Format::Json);
+        assert_eq!(parse_target(&rendered, Format::Json).unwrap_err().field, "pods[0].sandbox.volumes[1].secret");
+
+        target.secrets.insert("datadog".to_owned(), Files::from([("api-key".to_owned(), "hunter2".to_owned())]));
+        for format in [Format::Json, Format::Toml, Format::Yaml] {
+            let rendered = render_target(&target, format);
+            assert_eq!(parse_target(&rendered, format).unwrap(), target, "{:?}", format);
+        }
+
+        target.secrets.insert("datadog".to_owned(), Files::from([("../api-key".to_owned(), "hunter2".to_owned())]));
+        let rendered = render_target(&target, Format::Json);
+        assert_eq!(parse_target(&rendered, Format::Json).unwrap_err().field, "secrets.datadog.../api-key");
+    }
+
+    fn error_field(contents: &str, format: Format) -> String {
+        parse_pod(contents, format).unwrap_err().field
+    }
+
+    #[test]
+    fn errors_name_the_offending_field() {
+        let wrong_type = SHIPPER_TOML.replace("args = [", "privileged = \"yes\"\nargs = [");
+        assert_eq!(error_field(&wrong_type, Format::Toml), "containers.vector.privileged");
+
+        let unknown = SHIPPER_TOML.replace("[sandbox]", "[sandbox]\nnamspace = \"default\"");
+        assert_eq!(error_field(&unknown, Format::Toml), "sandbox.namspace");
+
+        let missing_image = SHIPPER_TOML.replace("image = \"docker.io/timberio/vector:latest\"", "");
+        assert_eq!(error_field(&missing_image, Format::Toml), "containers.vector");
+
+        let empty_image = SHIPPER_TOML.replace("docker.io/timberio/vector:latest", "");
+        assert_eq!(error_field(&empty_image, Format::Toml), "containers.vector.image");
+
+        let bad_env = SHIPPER_TOML.replace("VECTOR_THREADS", "");
+        assert_eq!(error_field(&bad_env, Format::Toml), "containers.vector.envs[1].name");
+
+        let bad_name = SHIPPER_TOML.replace("log-shipper", "Log_Shipper");
+        assert_eq!(error_field(&bad_name, Format::Toml), "sandbox.name");
+
+        let mismatched = SHIPPER_TOML.replace("[containers.vector]", "[containers.vector]\nname = \"other\"");
+        assert_eq!(error_field(&mismatched, Format::Toml), "containers.vector.name");
+
+        let bad_signal = SHIPPER_TOML.replace("[containers.vector]", "[containers.vector]\nstop_signal = \"quit\"");
+        assert_eq!(error_field(&bad_signal, Format::Toml), "containers.vector.stop_signal");
+
+        let empty_hook = format!("{}\n[containers.vector.lifecycle]\npre_stop = {{ type = \"exec\", command = [] }}\n", SHIPPER_TOML);
+        assert_eq!(error_field(&empty_hook, Format::Toml), "containers.vector.lifecycle.pre_stop.command");
+
+        let zero_period = SHIPPER_TOML.replace("[containers.vector]", "[containers.vector]\nliveness_probe = { check = { type = \"tcp_socket\", port = 8686 }, period = 0 }");
+        assert_eq!(error_field(&zero_period, Format::Toml), "containers.vector.liveness_probe.period");
+
+        let proxy = "\n[containers.proxy]\nimage = \"docker.io/envoyproxy/envoy:v1.31\"\ndepends_on = [\"vector\"]\n";
+        let cycle = SHIPPER_TOML.replace("[containers.vector]", "[containers.vector]\ndepends_on = [\"proxy\"]") + proxy;
+        assert!(error_field(&cycle, Format::Toml).ends_with(".depends_on"));
+        let unknown_dep = SHIPPER_TOML.replace("[containers.vector]", "[containers.vector]\ndepends_on = [\"proxy\"]");
+        assert_eq!(error_field(&unknown_dep, Format::Toml), "containers.vector.depends_on");
+        assert!(parse_pod(&(SHIPPER_TOML.to_owned() + proxy), Format::Toml).is_ok());
+
+        let only_sidecars = SHIPPER_TOML.replace("[containers.vector]", "[containers.vector]\nsidecar = true");
+        assert_eq!(error_field(&only_sidecars, Format::Toml), "containers");
+        let no_containers = SHIPPER_TOML.split("[containers.vector]").next().unwrap()
+            .replace("[sandbox]", "containers = {}\n\n[sandbox]");
+        let error = parse_pod(&no_containers, Format::Toml).unwrap_err();
+        assert_eq!((error.field.as_str(), error.message.as_str()), ("containers", "a pod needs at least one container"));
+
+        let version = SHIPPER_TOML.replace("hyphae/v1", "hyphae/v0");
+        assert_eq!(error_field(&version, Format::Toml), "api_version");
+    }
+
+    #[test]
+    fn json_errors_name_the_offending_field() {
+        let json = render_pod(&shipper(), Format::Json)
+            .replace("\"--config\"", "3");
+        assert_eq!(error_field(&json, Format::Json), "containers.vector.args[0]");
+    }
+
+    #[test]
+    fn duplicate_uids_are_rejected() {
+        let pod = shipper();
+        let manifest = TargetManifest {
+            api_version: API_VERSION.to_owned(),
+            pods: vec![pod.clone(), pod],
+            configs: HashMap::new(),
+            secrets: HashMap::new(),
+        };
+        let json = serde_json::to_string(&manifest).unwrap();
+        assert_eq!(parse_target(&json, Format::Json).unwrap_err().field, "pods[1].sandbox.uid");
+    }
+}
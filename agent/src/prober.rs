@@ -0,0 +1,213 @@
+use tokio::sync::mpsc::Sender;
+use tokio::task::JoinHandle;
+use crate::common::*;
+use crate::runtime::{Check, Probe};
+use crate::state::{State, Target};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ProbeKind {
+    Liveness,
+    Readiness,
+    Startup,
+}
+
+/// Sent whenever a probe's verdict on a container changes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProbeResult {
+    pub id: CtrId,
+    pub kind: ProbeKind,
+    pub passing: bool,
+}
+
+/// Turns individual checks into a verdict. A probe passes as soon as a check does, and fails once
+/// enough checks in a row have.
+struct Verdict {
+    failure_threshold: u32,
+    failures: u32,
+    /// None until there's a verdict. Liveness probes start out passing, the rest with no verdict.
+    passing: Option<bool>,
+}
+
+impl Verdict {
+    fn new(kind: ProbeKind, failure_threshold: u32) -> Verdict {
+        let passing = if kind == ProbeKind::Liveness { Some(true) } else { None };
+        Verdict { failure_threshold: failure_threshold.max(1), failures: 0, passing }
+    }
+
+    /// Returns the new verdict if this check changed it.
+    fn observe(&mut self, ok: bool) -> Option<bool> {
+        self.failures = if ok { 0 } else { self.failures + 1 };
+        let passing = match ok {
+            true => Some(true),
+            false if self.failures >= self.failure_threshold => Some(false),
+            false => self.passing,
+        };
+        if passing == self.passing { return None; }
+        self.passing = passing;
+        passing
+    }
+}
+
+/// Runs the probes of every running container that has any, and reports their verdicts to the control loop.
+pub struct Prober {
+    probes: HashMap<(CtrId, ProbeKind), JoinHandle<()>>,
+    results: Sender<ProbeResult>,
+}
+
+impl Prober {
+    pub fn new(results: Sender<ProbeResult>) -> Prober {
+        Prober { probes: HashMap::new(), results }
+    }
+
+    /// Start probing containers that have started running, and stop probing the ones that no longer are.
+    /// A container's startup probe runs alone until it passes.
+    pub fn sync(&mut self, state: &State, target: &Target, rsc: &RuntimeClient) {
+        let mut wanted = HashMap::new();
+        for (uid, pod) in state.pods.iter() {
+            let podconfig = match target.pods.get(uid) {
+                Some(podconfig) => podconfig,
+                None => { continue; }
+            };
+            for (name, ctr) in pod.ctrs.iter() {
+                if ctr.state != cri::ContainerState::ContainerRunning { continue; }
+                let config = match podconfig.containers.get(name) {
+                    Some(config) => config,
+                    None => { continue; }
+                };
+                let probes = match config.startup_probe {
+                    Some(ref probe) if !state.started.contains(&ctr.id) => vec![(ProbeKind::Startup, probe)],
+                    _ => {
+                        let liveness = config.liveness_probe.as_ref().map(|probe| (ProbeKind::Liveness, probe));
+                        let readiness = config.readiness_probe.as_ref().map(|probe| (ProbeKind::Readiness, probe));
+                        liveness.into_iter().chain(readiness).collect()
+                    }
+                };
+                for (kind, probe) in probes {
+                    wanted.insert((ctr.id.clone(), kind), probe.clone());
+                }
+            }
+        }
+        self.probes.retain(|key, handle| {
+            let keep = wanted.contains_key(key);
+            if !keep { handle.abort(); }
+            keep
+        });
+        for (key, probe) in wanted {
+            if self.probes.contains_key(&key) { continue; }
+            let (id, kind) = key.clone();
+            let handle = tokio::spawn(run_probe(rsc.clone(), id, kind, probe, self.results.clone()));
+            self.probes.insert(key, handle);
+        }
+    }
+}
+
+impl Drop for Prober {
+    fn drop(&mut self) {
+        for handle in self.probes.values() {
+            handle.abort();
+        }
+    }
+}
+
+async fn run_probe(mut rsc: RuntimeClient, id: CtrId, kind: ProbeKind, probe: Probe, results: Sender<ProbeResult>) {
+    tokio::time::sleep(Duration::from_secs(probe.initial_delay)).await;
+    let timeout = Duration::from_secs(probe.timeout);
+    let mut verdict = Verdict::new(kind, probe.failure_threshold);
+    let mut interval = tokio::time::interval(Duration::from_secs(probe.period.max(1)));
+    loop {
+        interval.tick().await;
+        let ok = match tokio::time::timeout(timeout, run_check(&mut rsc, &id, &probe.check, timeout)).await {
+            Ok(Ok(())) => true,
+            Ok(Err(e)) => {
+                println!("{:?} probe of container {} failed: {}", kind, id, e);
+                false
+            }
+            Err(_) => {
+                println!("{:?} probe of container {} timed out after {:?}", kind, id, timeout);
+                false
+            }
+        };
+        if let Some(passing) = verdict.observe(ok) {
+            if results.send(ProbeResult { id: id.clone(), kind, passing }).await.is_err() { return; }
+            // A startup probe's first verdict is its last.
+            if kind == ProbeKind::Startup { return; }
+        }
+    }
+}
+
+/// Run a check against a container, giving up after `timeout`. Also used for lifecycle hooks.
+pub async fn run_check(rsc: &mut RuntimeClient, id: &CtrId, check: &Check, timeout: Duration) -> Result<(), String> {
+    match check {
+        Check::Exec { command } => {
+            let resp = rsc.exec_sync(id.clone(), command.clone(), timeout.as_secs() as i64)
+                .await
+                .map_err(|e| e.message().to_owned())?;
+            if resp.exit_code != 0 {
+                let stderr = String::from_utf8_lossy(&resp.stderr);
+                return Err(format!("{:?} exited with {}: {}", command, resp.exit_code, stderr.trim()));
+            }
+            Ok(())
+        }
+        Check::HttpGet { path, port } => {
+            let ip = rsc.container_ip(id.clone()).await.map_err(|e| e.message().to_owned())?;
+            let client = reqwest::Client::builder()
+                .timeout(timeout)
+                .build()
+                .map_err(|e| e.to_string())?;
+            client.get(format!("http://{}:{}{}", ip, port, path))
+                .send()
+                .await
+                .and_then(|resp| resp.error_for_status())
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        }
+        Check::TcpSocket { port } => {
+            let ip = rsc.container_ip(id.clone()).await.map_err(|e| e.message().to_owned())?;
+            connect(&format!("{}:{}", ip, port), timeout).await
+        }
+    }
+}
+
+async fn connect(addr: &str, timeout: Duration) -> Result<(), String> {
+    match tokio::time::timeout(timeout, tokio::net::TcpStream::connect(addr)).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(format!("couldn't connect to {}: {}", addr, e)),
+        Err(_) => Err(format!("couldn't connect to {} within {:?}", addr, timeout)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verdict_changes_after_threshold() {
+        let mut verdict = Verdict::new(ProbeKind::Liveness, 3);
+        let changes: Vec<Option<bool>> = [false, false, true, false, false, false, false, true]
+            .into_iter()
+            .map(|ok| verdict.observe(ok))
+            .collect();
+        assert_eq!(changes, vec![None, None, None, None, None, Some(false), None, Some(true)]);
+    }
+
+    #[test]
+    fn readiness_starts_without_a_verdict() {
+        let mut verdict = Verdict::new(ProbeKind::Readiness, 2);
+        let changes: Vec<Option<bool>> = [false, true, true, false, false]
+            .into_iter()
+            .map(|ok| verdict.observe(ok))
+            .collect();
+        assert_eq!(changes, vec![None, Some(true), None, None, Some(false)]);
+        let mut verdict = Verdict::new(ProbeKind::Startup, 2);
+        assert_eq!((verdict.observe(false), verdict.observe(false)), (None, Some(false)));
+    }
+
+    #[tokio::test]
+    async fn tcp_checks_need_a_listener() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        assert_eq!(connect(&addr, Duration::from_secs(1)).await, Ok(()));
+        drop(listener);
+        assert!(connect(&addr, Duration::from_secs(1)).await.is_err());
+    }
+}
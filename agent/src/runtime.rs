@@ -3,52 +3,597 @@ use cri::runtime_service_client::RuntimeServiceClient;
 use k8s_cri::v1 as cri;
 use tonic::Status;
 use tokio::sync::Semaphore;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 use crate::common::*;
+use crate::volumes;
 
 type RuntimeService = RuntimeServiceClient<tonic::transport::Channel>;
 type ImageService = ImageServiceClient<tonic::transport::Channel>;
 
 const MAX_IMAGE_PULL_CONCURRENCY: usize = 15;
+// CPU limits are enforced as a quota of CPU time per period. The runtime won't take less than 1ms.
+const CPU_PERIOD_US: u64 = 100_000;
+const CPU_QUOTA_MIN_US: u64 = 1_000;
+const BYTES_PER_MB: u64 = 1024 * 1024;
 
-#[derive(Clone, Debug)]
+// Every sandbox and container we create is stamped with these, so that we can tell ours apart
+// from anything else sharing containerd.
+pub const NAME_LABEL: &'static str = "name";
+pub const AGENT_LABEL: &'static str = "hyphae.io/agent";
+pub const POD_UID_LABEL: &'static str = "hyphae.io/pod-uid";
+pub const GENERATION_LABEL: &'static str = "hyphae.io/generation";
+// A hash of the config something was created from, so that we can tell when it's out of date.
+pub const SPEC_HASH_LABEL: &'static str = "hyphae.io/spec-hash";
+// How to stop a container. By the time it's being stopped, its config may be long gone.
+pub const GRACE_PERIOD_LABEL: &'static str = "hyphae.io/grace-period";
+pub const STOP_SIGNAL_LABEL: &'static str = "hyphae.io/stop-signal";
+// The names of the containers it depends on, comma-separated, so that they can be stopped after it.
+pub const DEPENDS_ON_LABEL: &'static str = "hyphae.io/depends-on";
+// The container's PreStop hook, as JSON.
+pub const PRE_STOP_ANNOTATION: &'static str = "hyphae.io/pre-stop";
+// The resources the container or sandbox was created with, as JSON. They're left out of spec hashes:
+// containers' can be changed without recreating them, and a sandbox only needs recreating when its
+// containers need more than it has.
+pub const RESOURCES_ANNOTATION: &'static str = "hyphae.io/resources";
+
+// These structs double as the manifest schema. See manifest.rs for the format and its validation.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct PodConfig {
+    #[serde(default)]
+    pub restart_policy: RestartPolicy,
+    #[serde(rename = "sandbox")]
     pub config: SandBoxConfig,
     pub containers: HashMap<String, ContainerConfig>,
+    /// Run one at a time, in order, each until it exits 0, before any of the containers are created.
+    /// They're run again whenever the sandbox is recreated.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub init_containers: Vec<ContainerConfig>,
+}
+
+impl PodConfig {
+    /// A container or init container, by name.
+    pub fn container(&self, name: &str) -> Option<&ContainerConfig> {
+        self.containers.get(name).or_else(|| self.init_containers.iter().find(|ctr| ctr.name == name))
+    }
+
+    /// The config to create the pod's sandbox from, limited to what its containers are.
+    pub fn sandbox_config(&self, attempt: u32) -> SandBoxConfig {
+        SandBoxConfig { attempt, resources: self.resources(), ..self.config.clone() }
+    }
+
+    /// Enough for all the containers at once, or for any one init container, since they run alone.
+    /// A limit is only set if every container has one.
+    pub fn resources(&self) -> Resources {
+        let limit = |get: &dyn Fn(&Resources) -> Option<u64>| -> Option<u64> {
+            let sum = self.containers.values().map(|ctr| get(&ctr.resources)).sum::<Option<u64>>()?;
+            self.init_containers.iter().try_fold(sum, |max, ctr| Some(max.max(get(&ctr.resources)?)))
+        };
+        // Shares are a weight rather than a limit, so containers without any just don't add to them.
+        let shares = |ctr: &ContainerConfig| ctr.resources.cpu_shares.unwrap_or(0);
+        let cpu_shares = self.containers.values().map(shares).sum::<u64>()
+            .max(self.init_containers.iter().map(shares).max().unwrap_or(0));
+        let all = self.containers.values().chain(self.init_containers.iter());
+        let page_sizes: std::collections::BTreeSet<&String> = all.flat_map(|ctr| ctr.resources.hugepages_mb.keys()).collect();
+        Resources {
+            cpu_shares: Some(cpu_shares).filter(|&shares| shares > 0),
+            cpu_millis: limit(&|r| r.cpu_millis),
+            memory_mb: limit(&|r| r.memory_mb),
+            memory_swap_mb: limit(&|r| r.memory_swap_mb),
+            pids: limit(&|r| r.pids),
+            hugepages_mb: page_sizes.into_iter()
+                .filter_map(|size| Some((size.clone(), limit(&|r| r.hugepages_mb.get(size).copied())?)))
+                .collect(),
+            ..Default::default()
+        }
+    }
 }
 
-#[derive(Clone, Debug)]
+/// Whether a pod's service containers are started again after they exit. Jobs go by their backoff_limit instead.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    #[default]
+    Always,
+    /// Only if they exit with a non-zero code.
+    OnFailure,
+    Never,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct SandBoxConfig {
     pub name: String,
     pub uid: String,
-    pub resources: Option<cri::LinuxContainerResources>,
+    /// What the pod's containers are limited to between them. Filled in when planning, not part of the spec.
+    #[serde(skip)]
+    pub resources: Resources,
+    /// Which sandbox this is for the pod, counting from 0. Filled in when planning, not part of the spec.
+    #[serde(skip)]
+    pub attempt: u32,
+    #[serde(default = "default_namespace", skip_serializing_if = "is_default_namespace")]
     pub namespace: String,
+    /// Bumped by whoever writes the spec whenever it changes. Recorded on everything we create for the pod.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub generation: u64,
+    /// Storage for the pod's containers to mount. Created along with the sandbox, and removed with it.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub volumes: Vec<Volume>,
+}
+
+fn default_namespace() -> String {
+    "default".to_owned()
 }
 
-#[derive(Clone, Debug)]
+fn is_default_namespace(namespace: &String) -> bool {
+    *namespace == default_namespace()
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct ContainerConfig {
+    /// Filled in from the key in PodConfig.containers if left out. Required for init containers.
+    #[serde(default)]
     pub name: String,
     pub image: String,
+    /// If empty, the image's entrypoint is used.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub command: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub args: Vec<String>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub working_dir: String,
-    pub envs: Vec<(String, String)>,
+    /// Written as a list of { name, value } tables so that their order is kept.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub envs: Vec<EnvVar>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub volume_mounts: Vec<VolumeMount>,
+    #[serde(default, skip_serializing_if = "Resources::is_empty")]
+    pub resources: Resources,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
     pub privileged: bool,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub run_mode: RunMode,
+    /// Supports the pod's other containers, e.g. by forwarding their logs. Once they've all exited
+    /// successfully for good, sidecars are stopped so that the pod can finish.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub sidecar: bool,
+    /// How many times a Job is retried after failing before we give up on it. Ignored for services.
+    #[serde(default = "default_backoff_limit", skip_serializing_if = "is_default_backoff_limit")]
+    pub backoff_limit: u32,
+    /// How long, in seconds, the container gets to exit after being asked to stop before it's killed.
+    #[serde(default = "default_grace_period", skip_serializing_if = "is_default_grace_period")]
+    pub grace_period: u64,
+    /// Sent to ask the container to stop instead of the image's stop signal, e.g. "SIGQUIT".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop_signal: Option<String>,
+    #[serde(default, skip_serializing_if = "Lifecycle::is_empty")]
+    pub lifecycle: Lifecycle,
+    /// Other containers in the pod that have to be ready (or for Jobs, have succeeded) before this
+    /// one is started, and that are only stopped after it when the pod is torn down.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub depends_on: Vec<Name>,
+    /// Restart the container if this fails.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub liveness_probe: Option<Probe>,
+    /// The container is only ready for traffic while this passes. Without one, it's ready while it's running.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub readiness_probe: Option<Probe>,
+    /// Run instead of the other probes until it first passes, for containers that are slow to start.
+    /// Restart the container if it fails.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub startup_probe: Option<Probe>,
+    /// Which container this is under its name, counting from 0. Filled in when planning, not part of the spec.
+    #[serde(skip)]
+    pub attempt: u32,
+}
+
+/// What a container is meant to be doing.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RunMode {
+    /// Running, always. Exits are restarted.
+    #[default]
+    Service,
+    /// Run to completion, i.e. exit 0. Failures are retried up to the container's backoff_limit.
+    Job,
+}
+
+/// What a container may use. Anything left out is unlimited.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Resources {
+    /// Its weight when CPUs are contended, relative to other containers'.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_shares: Option<u64>,
+    /// CPU time it may use, in thousandths of a CPU.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_millis: Option<u64>,
+    /// The CPUs and memory nodes it may run on, e.g. "0-3,6".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpuset_cpus: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpuset_mems: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_mb: Option<u64>,
+    /// Memory and swap together, so at least memory_mb.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_swap_mb: Option<u64>,
+    /// How many processes and threads it may have.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pids: Option<u64>,
+    /// By page size, e.g. "2MB".
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub hugepages_mb: BTreeMap<String, u64>,
+}
+
+impl Resources {
+    pub fn is_empty(&self) -> bool {
+        *self == Resources::default()
+    }
+
+    /// Whether a sandbox limited to these has room for containers needing `needed`.
+    pub fn covers(&self, needed: &Resources) -> bool {
+        let covers = |has: Option<u64>, needs: Option<u64>| match (has, needs) {
+            (None, _) => true,
+            (Some(has), Some(needs)) => has >= needs,
+            (Some(_), None) => false,
+        };
+        // Shares are only a weight, so they're no reason to recreate anything.
+        covers(self.cpu_millis, needed.cpu_millis)
+            && covers(self.memory_mb, needed.memory_mb)
+            && covers(self.memory_swap_mb, needed.memory_swap_mb)
+            && covers(self.pids, needed.pids)
+            && self.hugepages_mb.iter().all(|(page_size, &has)| covers(Some(has), needed.hugepages_mb.get(page_size).copied()))
+    }
+
+    /// Whether a container's limits can be changed from these to `new` without recreating it. The
+    /// runtime takes a limit that's left out to mean no change, so they can't be lifted that way.
+    pub fn can_change_to(&self, new: &Resources) -> bool {
+        let kept = |old: bool, new: bool| !old || new;
+        kept(self.cpu_shares.is_some(), new.cpu_shares.is_some())
+            && kept(self.cpu_millis.is_some(), new.cpu_millis.is_some())
+            && kept(self.cpuset_cpus.is_some(), new.cpuset_cpus.is_some())
+            && kept(self.cpuset_mems.is_some(), new.cpuset_mems.is_some())
+            && kept(self.memory_mb.is_some(), new.memory_mb.is_some())
+            && kept(self.memory_swap_mb.is_some(), new.memory_swap_mb.is_some())
+            && kept(self.pids.is_some(), new.pids.is_some())
+            && self.hugepages_mb.keys().all(|page_size| new.hugepages_mb.contains_key(page_size))
+    }
+
+    pub fn to_cri(&self) -> Option<cri::LinuxContainerResources> {
+        if self.is_empty() { return None; }
+        let bytes = |mb: Option<u64>| mb.map_or(0, |mb| (mb * BYTES_PER_MB) as i64);
+        let mut unified = HashMap::new();
+        if let Some(pids) = self.pids {
+            unified.insert("pids.max".to_owned(), pids.to_string());
+        }
+        Some(cri::LinuxContainerResources {
+            cpu_shares: self.cpu_shares.unwrap_or(0) as i64,
+            cpu_period: self.cpu_millis.map_or(0, |_| CPU_PERIOD_US as i64),
+            cpu_quota: self.cpu_millis.map_or(0, |millis| (millis * CPU_PERIOD_US / 1000).max(CPU_QUOTA_MIN_US) as i64),
+            cpuset_cpus: self.cpuset_cpus.clone().unwrap_or_default(),
+            cpuset_mems: self.cpuset_mems.clone().unwrap_or_default(),
+            memory_limit_in_bytes: bytes(self.memory_mb),
+            memory_swap_limit_in_bytes: bytes(self.memory_swap_mb),
+            hugepage_limits: self.hugepages_mb.iter()
+                .map(|(page_size, &mb)| cri::HugepageLimit { page_size: page_size.clone(), limit: mb * BYTES_PER_MB })
+                .collect(),
+            unified,
+            ..Default::default()
+        })
+    }
+}
+
+/// Hooks run in or against a container as it starts and stops.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Lifecycle {
+    /// Run once the container has started. If it fails, the container is killed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_start: Option<Hook>,
+    /// Run before the container is asked to stop, within its grace period. The container is
+    /// stopped whether or not it succeeds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre_stop: Option<Hook>,
+}
+
+impl Lifecycle {
+    fn is_empty(&self) -> bool {
+        self.post_start.is_none() && self.pre_stop.is_none()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Hook {
+    /// Run a command in the container. It fails if it exits non-zero.
+    Exec { command: Vec<String> },
+    /// GET a path on the pod's IP. It fails unless the response is a success.
+    HttpGet { path: String, port: u16 },
+}
+
+/// A check run on a running container every so often.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Probe {
+    pub check: Check,
+    /// Seconds to wait after the container starts before the first check.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub initial_delay: u64,
+    /// Seconds between checks.
+    #[serde(default = "default_probe_period", skip_serializing_if = "is_default_probe_period")]
+    pub period: u64,
+    /// Seconds a check gets before it counts as failed.
+    #[serde(default = "default_probe_timeout", skip_serializing_if = "is_default_probe_timeout")]
+    pub timeout: u64,
+    /// How many checks in a row have to fail before the probe does.
+    #[serde(default = "default_failure_threshold", skip_serializing_if = "is_default_failure_threshold")]
+    pub failure_threshold: u32,
+}
+
+/// What a probe checks. Like a Hook, plus whether a port is open.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Check {
+    Exec { command: Vec<String> },
+    HttpGet { path: String, port: u16 },
+    /// Connect to a port on the pod's IP.
+    TcpSocket { port: u16 },
+}
+
+impl From<Hook> for Check {
+    fn from(hook: Hook) -> Check {
+        match hook {
+            Hook::Exec { command } => Check::Exec { command },
+            Hook::HttpGet { path, port } => Check::HttpGet { path, port },
+        }
+    }
+}
+
+/// An environment variable, set either to `value` or to what `value_from` refers to.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EnvVar {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub value: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value_from: Option<EnvSource>,
+}
+
+#[cfg(test)]
+impl EnvVar {
+    pub fn new(name: &str, value: &str) -> EnvVar {
+        EnvVar { name: name.to_owned(), value: value.to_owned(), value_from: None }
+    }
+}
+
+/// Where an environment variable's value comes from. Looked up when the container is created.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum EnvSource {
+    /// A file in one of the node's configs.
+    Config { config: Name, key: String },
+    /// A file in one of the node's secrets.
+    Secret { secret: Name, key: String },
+    /// Something about the pod, or the node it's on.
+    Field { field: PodField },
+    /// One of the container's own limits.
+    Resource { resource: ResourceField },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PodField {
+    PodUid,
+    PodName,
+    Namespace,
+    NodeName,
+    PodIp,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResourceField {
+    CpuMillis,
+    MemoryMb,
+}
+
+impl ResourceField {
+    pub fn limit(&self, resources: &Resources) -> Option<u64> {
+        match self {
+            ResourceField::CpuMillis => resources.cpu_millis,
+            ResourceField::MemoryMb => resources.memory_mb,
+        }
+    }
+}
+
+/// The values of a container's env vars that come from the node's configs and secrets, by env var name.
+/// Left out of Debug output, since they may well be secret.
+#[derive(Clone, Default, PartialEq)]
+pub struct EnvValues(pub HashMap<String, String>);
+
+impl std::fmt::Debug for EnvValues {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
+
+/// What an env var is set to in the container, given the pod's IP if it needed one.
+fn resolve_env(env: &EnvVar, config: &ContainerConfig, sandbox_config: &SandBoxConfig, values: &EnvValues, pod_ip: &str)
+    -> Result<String, String>
+{
+    let value = match env.value_from {
+        None => env.value.clone(),
+        Some(EnvSource::Resource { resource }) => {
+            resource.limit(&config.resources)
+                .ok_or_else(|| format!("{}: the container has no {:?} limit", env.name, resource))?
+                .to_string()
+        }
+        Some(EnvSource::Field { field }) => match field {
+            PodField::PodUid => sandbox_config.uid.clone(),
+            PodField::PodName => sandbox_config.name.clone(),
+            PodField::Namespace => sandbox_config.namespace.clone(),
+            PodField::NodeName => node_name(),
+            PodField::PodIp => pod_ip.to_owned(),
+        },
+        Some(EnvSource::Config { ref config, ref key }) | Some(EnvSource::Secret { secret: ref config, ref key }) => {
+            values.0.get(&env.name)
+                .cloned()
+                .ok_or_else(|| format!("{}: {:?} has no key {:?}", env.name, config, key))?
+        }
+    };
+    Ok(value)
+}
+
+/// Storage shared by a pod's containers. Everything but host paths lives under the agent's data root.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Volume {
+    /// An empty directory on the node's disk.
+    EmptyDir { name: Name },
+    /// An empty directory in memory, of at most `size_mb` if set.
+    Tmpfs {
+        name: Name,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        size_mb: Option<u64>,
+    },
+    /// A file or directory that's already on the node.
+    HostPath {
+        name: Name,
+        path: String,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        read_only: bool,
+    },
+    /// The files of one of the node's configs. Always read-only, and kept up to date as the config changes.
+    Config { name: Name, config: Name },
+    /// Like a config, but kept in memory and only readable by root.
+    Secret { name: Name, secret: Name },
+}
+
+impl Volume {
+    pub fn name(&self) -> &str {
+        match self {
+            Volume::EmptyDir { name }
+            | Volume::Tmpfs { name, .. }
+            | Volume::HostPath { name, .. }
+            | Volume::Config { name, .. }
+            | Volume::Secret { name, .. } => name,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VolumeMount {
+    /// One of the pod's volumes.
+    pub name: Name,
+    pub mount_path: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub read_only: bool,
+}
+
+// Fields left at their defaults aren't serialized, so that adding a field doesn't change the spec
+// hash of every existing config.
+fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+fn default_probe_period() -> u64 {
+    10
+}
+
+fn is_default_probe_period(period: &u64) -> bool {
+    *period == default_probe_period()
+}
+
+fn default_probe_timeout() -> u64 {
+    1
+}
+
+fn is_default_probe_timeout(timeout: &u64) -> bool {
+    *timeout == default_probe_timeout()
+}
+
+fn default_failure_threshold() -> u32 {
+    3
+}
+
+fn is_default_failure_threshold(threshold: &u32) -> bool {
+    *threshold == default_failure_threshold()
+}
+
+fn default_backoff_limit() -> u32 {
+    6
+}
+
+fn is_default_backoff_limit(limit: &u32) -> bool {
+    *limit == default_backoff_limit()
+}
+
+pub fn default_grace_period() -> u64 {
+    30
+}
+
+fn is_default_grace_period(grace_period: &u64) -> bool {
+    *grace_period == default_grace_period()
+}
+
+/// A hash of a config that stays the same across restarts and releases, unlike std's hashers. Only
+/// fields set to something other than their default go into it.
+fn spec_hash<T: Serialize>(config: &T) -> String {
+    // FNV-1a over the JSON encoding.
+    let bytes = serde_json::to_vec(config).expect("Configs are always serializable.");
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x}", hash)
+}
+
+impl ContainerConfig {
+    pub fn spec_hash(&self) -> String {
+        spec_hash(&ContainerConfig { resources: Resources::default(), ..self.clone() })
+    }
 }
 
 impl SandBoxConfig {
-    pub fn to_cri_config(self) -> cri::PodSandboxConfig {
+    /// Bumping the generation on its own doesn't change what we'd create, so it's left out.
+    pub fn spec_hash(&self) -> String {
+        spec_hash(&SandBoxConfig { generation: 0, ..self.clone() })
+    }
+
+    /// The labels identifying something we created for this pod.
+    fn owner_labels(&self, agent_id: &str, name: &str) -> HashMap<String, String> {
+        HashMap::from([
+            (NAME_LABEL.to_owned(), name.to_owned()),
+            (AGENT_LABEL.to_owned(), agent_id.to_owned()),
+            (POD_UID_LABEL.to_owned(), self.uid.clone()),
+            (GENERATION_LABEL.to_owned(), self.generation.to_string()),
+        ])
+    }
+
+    pub fn to_cri_config(self, agent_id: &str) -> cri::PodSandboxConfig {
+        let mut sandbox_labels = self.owner_labels(agent_id, &self.name);
+        sandbox_labels.insert(SPEC_HASH_LABEL.to_owned(), self.spec_hash());
+        let mut sandbox_annotations = HashMap::new();
+        if !self.resources.is_empty() {
+            let resources = serde_json::to_string(&self.resources).expect("Resources are always serializable.");
+            sandbox_annotations.insert(RESOURCES_ANNOTATION.to_owned(), resources);
+        }
         let metadata = cri::PodSandboxMetadata {
             name: self.name.clone(),
             uid: self.uid,
             namespace: self.namespace,
-            attempt: 0,
+            attempt: self.attempt,
         };
-        let sandbox_labels = HashMap::from([
-            ("name".to_owned(), self.name.clone()),
-        ]);
         let linux_options = cri::LinuxPodSandboxConfig {
             cgroup_parent: "".to_owned(),
-            resources: self.resources,
+            resources: self.resources.to_cri(),
             security_context: Some(cri::LinuxSandboxSecurityContext {
                 namespace_options: Some(cri::NamespaceOption {
                     network: cri::NamespaceMode::Pod.into(),
@@ -67,7 +612,7 @@ impl SandBoxConfig {
             dns_config: None,
             log_directory: "/var/log/pods/".to_owned() + &self.name,
             port_mappings: vec![],
-            annotations: HashMap::new(),
+            annotations: sandbox_annotations,
             windows: None,
         };
         config
@@ -78,11 +623,15 @@ impl SandBoxConfig {
 pub struct RuntimeClient {
     rsc: RuntimeService,
     isc: ImageService,
-    sem: Arc<Semaphore>
+    sem: Arc<Semaphore>,
+    agent_id: Arc<String>,
+    data_root: Arc<PathBuf>,
 }
 
 impl RuntimeClient {
-    pub async fn connect() -> Result<RuntimeClient, tonic::transport::Error> {
+    /// Connect to containerd. Everything created through this client is labelled as belonging to `agent_id`,
+    /// and pods' volumes are kept under `data_root`.
+    pub async fn connect(agent_id: String, data_root: PathBuf) -> Result<RuntimeClient, tonic::transport::Error> {
         use hyper_util::rt::TokioIo;
         use tokio::net::UnixStream;
         let channel = tonic::transport::Endpoint::try_from("http://[::]:50051")?
@@ -94,8 +643,17 @@ impl RuntimeClient {
         let rsc = RuntimeService::new(channel.clone());
         let isc = ImageService::new(channel.clone());
         let sem = Arc::new(Semaphore::new(MAX_IMAGE_PULL_CONCURRENCY));
-        Ok(RuntimeClient { rsc, isc, sem })
+        Ok(RuntimeClient { rsc, isc, sem, agent_id: Arc::new(agent_id), data_root: Arc::new(data_root) })
     }
+
+    pub fn agent_id(&self) -> &str {
+        &self.agent_id
+    }
+
+    pub fn data_root(&self) -> &Path {
+        &self.data_root
+    }
+
     pub async fn pull_image(&mut self, name: String) -> Result<String, Status> {
         let spec = cri:: ImageSpec {
             image: name.clone(),
@@ -126,25 +684,69 @@ impl RuntimeClient {
     }
     
     pub async fn create_sandbox(&mut self, config: SandBoxConfig) -> Result<String, Status> {
-        let config = config.to_cri_config();
+        let pod_volumes = config.volumes.clone();
+        let config = config.to_cri_config(&self.agent_id);
         let request = cri::RunPodSandboxRequest {
             config: Some(config.clone()),
             runtime_handler: String::new(),
         };
-        return self.rsc.run_pod_sandbox(request)
+        let pod_id = self.rsc.run_pod_sandbox(request)
             .await
-            .map(|m| m.into_inner().pod_sandbox_id);
+            .map(|m| m.into_inner().pod_sandbox_id)?;
+        // A sandbox without its volumes is no use to anyone, so don't leave it behind.
+        if let Err(e) = volumes::create(&self.data_root, &pod_id, &pod_volumes).await {
+            if let Err(e) = self.remove_pod(pod_id.clone()).await {
+                log_err(e);
+            }
+            return Err(Status::internal(format!("Could not create volumes for pod {}: {}", pod_id, e)));
+        }
+        Ok(pod_id)
     }
     
-    pub async fn create_container(&mut self, pod_id: String, config: ContainerConfig, sandbox_config: SandBoxConfig)
+    pub async fn create_container(
+        &mut self,
+        pod_id: String,
+        config: ContainerConfig,
+        sandbox_config: SandBoxConfig,
+        env_values: EnvValues,
+    )
         -> Result<String, Status>
     {
         let image_id = self.pull_image(config.image.clone()).await?;
-        let container_labels = HashMap::from([
-            ("name".to_owned(), config.name.clone()),
-        ]);
+        let needs_ip = config.envs.iter().any(|env| env.value_from == Some(EnvSource::Field { field: PodField::PodIp }));
+        let pod_ip = if needs_ip { self.pod_ip(pod_id.clone()).await? } else { String::new() };
+        let envs = config.envs.iter()
+            .map(|env| {
+                let value = resolve_env(env, &config, &sandbox_config, &env_values, &pod_ip)?;
+                Ok(cri::KeyValue { key: env.name.clone(), value })
+            })
+            .collect::<Result<Vec<_>, String>>()
+            .map_err(Status::failed_precondition)?;
+        let mut container_labels = sandbox_config.owner_labels(&self.agent_id, &config.name);
+        container_labels.insert(SPEC_HASH_LABEL.to_owned(), config.spec_hash());
+        container_labels.insert(GRACE_PERIOD_LABEL.to_owned(), config.grace_period.to_string());
+        if let Some(ref signal) = config.stop_signal {
+            container_labels.insert(STOP_SIGNAL_LABEL.to_owned(), signal.clone());
+        }
+        if !config.depends_on.is_empty() {
+            container_labels.insert(DEPENDS_ON_LABEL.to_owned(), config.depends_on.join(","));
+        }
+        if !volumes::created(&self.data_root, &pod_id, &sandbox_config.volumes) {
+            return Err(Status::unavailable(format!("The volumes of pod {} haven't been created yet", pod_id)));
+        }
+        let mounts = volumes::mounts(&self.data_root, &pod_id, &sandbox_config.volumes, &config.volume_mounts)
+            .map_err(Status::invalid_argument)?;
+        let mut container_annotations = HashMap::new();
+        if let Some(ref hook) = config.lifecycle.pre_stop {
+            let hook = serde_json::to_string(hook).expect("Hooks are always serializable.");
+            container_annotations.insert(PRE_STOP_ANNOTATION.to_owned(), hook);
+        }
+        if !config.resources.is_empty() {
+            let resources = serde_json::to_string(&config.resources).expect("Resources are always serializable.");
+            container_annotations.insert(RESOURCES_ANNOTATION.to_owned(), resources);
+        }
         let linux_options = cri::LinuxContainerConfig {
-            resources: None,
+            resources: config.resources.to_cri(),
             security_context: Some(cri::LinuxContainerSecurityContext {
                 privileged: config.privileged,
                 namespace_options: Some(cri::NamespaceOption {
@@ -157,25 +759,25 @@ impl RuntimeClient {
         let cri_container_config = cri::ContainerConfig {
             metadata: Some(cri::ContainerMetadata {
                 name: config.name.clone(),
-                attempt: 0,
+                attempt: config.attempt,
             }),
             image: Some(cri::ImageSpec {
                 image: image_id,
                 annotations: HashMap::new(),
                 ..Default::default()
             }),
-            command: vec![config.command],
+            command: if config.command.is_empty() { vec![] } else { vec![config.command] },
             args: config.args,
             working_dir: config.working_dir,
-            envs: config.envs.into_iter().map(|(key, value)| cri::KeyValue { key, value }).collect(),
+            envs,
             labels: container_labels,
-            annotations: HashMap::new(),
-            log_path: config.name.clone() + &"-id.log", // TODO: Fix this
+            annotations: container_annotations,
+            log_path: format!("{}-{}.log", config.name, config.attempt), // TODO: Fix this
             linux: Some(linux_options),
             stdin_once: false,
             stdin: false,
             tty: false,
-            mounts: vec![],
+            mounts,
             devices: vec![],
             windows: None,
             cdi_devices: vec![],
@@ -183,7 +785,7 @@ impl RuntimeClient {
         let create_request = cri::CreateContainerRequest {
             pod_sandbox_id: pod_id,
             config: Some(cri_container_config),
-            sandbox_config: Some(sandbox_config.to_cri_config()),
+            sandbox_config: Some(sandbox_config.to_cri_config(&self.agent_id)),
         };
         
         self.rsc.create_container(create_request)
@@ -198,16 +800,99 @@ impl RuntimeClient {
             .map(|_| ())
     }
     
-    pub async fn stop_container(&mut self, container_id: String) -> Result<(), Status> {
+    /// Change a running container's limits. Not every runtime can, and none can lift a limit this way.
+    pub async fn update_container_resources(&mut self, id: String, resources: &Resources) -> Result<(), Status> {
+        let request = cri::UpdateContainerResourcesRequest {
+            container_id: id,
+            linux: resources.to_cri(),
+            windows: None,
+            annotations: HashMap::new(),
+        };
+        self.rsc.update_container_resources(request)
+            .await
+            .map(|_| ())
+    }
+
+    /// Ask the container to stop, and kill it if it hasn't after `timeout` seconds.
+    pub async fn stop_container(&mut self, container_id: String, timeout: i64) -> Result<(), Status> {
         let stop_req = cri::StopContainerRequest {
             container_id,
-            timeout: 0,
+            timeout,
         };
         self.rsc.stop_container(stop_req)
             .await
             .map(|_| ())
     }
     
+    pub async fn exec_sync(&mut self, container_id: String, cmd: Vec<String>, timeout: i64)
+        -> Result<cri::ExecSyncResponse, Status>
+    {
+        let exec_req = cri::ExecSyncRequest {
+            container_id,
+            cmd,
+            timeout,
+        };
+        self.rsc.exec_sync(exec_req)
+            .await
+            .map(|m| m.into_inner())
+    }
+
+    /// The IP of the pod a container is in.
+    pub async fn container_ip(&mut self, container_id: String) -> Result<String, Status> {
+        let list_req = cri::ListContainersRequest {
+            filter: Some(cri::ContainerFilter { id: container_id, ..Default::default() }),
+        };
+        let pod_sandbox_id = self.rsc.list_containers(list_req)
+            .await?
+            .into_inner()
+            .containers
+            .pop()
+            .ok_or_else(|| Status::not_found("no such container"))?
+            .pod_sandbox_id;
+        self.pod_ip(pod_sandbox_id).await
+    }
+
+    pub async fn pod_ip(&mut self, pod_sandbox_id: String) -> Result<String, Status> {
+        let status_req = cri::PodSandboxStatusRequest { pod_sandbox_id, verbose: false };
+        self.rsc.pod_sandbox_status(status_req)
+            .await?
+            .into_inner()
+            .status
+            .and_then(|status| status.network)
+            .map(|network| network.ip)
+            .filter(|ip| !ip.is_empty())
+            .ok_or_else(|| Status::not_found("the pod has no IP"))
+    }
+
+    pub async fn container_status(&mut self, container_id: String) -> Result<cri::ContainerStatus, Status> {
+        let status_req = cri::ContainerStatusRequest {
+            container_id,
+            verbose: false,
+        };
+        self.rsc.container_status(status_req)
+            .await?
+            .into_inner()
+            .status
+            .ok_or_else(|| Status::not_found("no status for container"))
+    }
+
+    /// The host pid of a container's main process, if the runtime's verbose status has it.
+    pub async fn container_pid(&mut self, container_id: String) -> Result<Option<i32>, Status> {
+        let status_req = cri::ContainerStatusRequest {
+            container_id,
+            verbose: true,
+        };
+        let info = self.rsc.container_status(status_req)
+            .await?
+            .into_inner()
+            .info;
+        Ok(info.get("info")
+            .and_then(|info| serde_json::from_str::<serde_json::Value>(info).ok())
+            .and_then(|info| info.get("pid")?.as_i64())
+            .filter(|pid| *pid > 0)
+            .map(|pid| pid as i32))
+    }
+
     pub async fn remove_container(&mut self, container_id: String) -> Result<(), Status> {
         let remove_req = cri::RemoveContainerRequest {
             container_id: container_id,
@@ -226,33 +911,144 @@ impl RuntimeClient {
         let remove_req = cri::RemovePodSandboxRequest {
             pod_sandbox_id: pod_id.clone()
         };
-        let remove_resp = self.rsc.remove_pod_sandbox(remove_req)
+        self.rsc.remove_pod_sandbox(remove_req).await?;
+        volumes::remove(&self.data_root, &pod_id)
             .await
-            .map(|_| ());
-    
-        return remove_resp;
+            .map_err(|e| Status::internal(format!("Could not remove volumes of pod {}: {}", pod_id, e)))
     }
     
-    pub async fn list_containers(&mut self) -> Result<cri::ListContainersResponse, Status> {
+    /// Only our own containers are listed if `owned_only` is set.
+    pub async fn list_containers(&mut self, owned_only: bool) -> Result<cri::ListContainersResponse, Status> {
         let list_req = cri::ListContainersRequest {
-            filter: None
+            filter: owned_only.then(|| cri::ContainerFilter {
+                label_selector: self.owner_selector(),
+                ..Default::default()
+            }),
         };
         self.rsc.list_containers(list_req)
             .await
             .map(|m| m.into_inner())
     }
     
-    pub async fn list_pods(&mut self) -> Result<cri::ListPodSandboxResponse, Status> {
+    /// Only our own sandboxes are listed if `owned_only` is set.
+    pub async fn list_pods(&mut self, owned_only: bool) -> Result<cri::ListPodSandboxResponse, Status> {
         let list_req = cri::ListPodSandboxRequest {
-            filter: None,
+            filter: owned_only.then(|| cri::PodSandboxFilter {
+                label_selector: self.owner_selector(),
+                ..Default::default()
+            }),
         };
         self.rsc.list_pod_sandbox(list_req)
             .await
             .map(|m| m.into_inner())
     }
 
+    fn owner_selector(&self) -> HashMap<String, String> {
+        HashMap::from([(AGENT_LABEL.to_owned(), self.agent_id.to_string())])
+    }
+
     pub async fn get_container_events(&mut self) -> Result<tonic::Streaming<cri::ContainerEventResponse>, tonic::Status> {
         self.rsc.get_container_events(cri::GetEventsRequest{}).await
             .map(|stream| stream.into_inner())
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pod(limits: &[(&str, Resources)], init_limits: &[Resources]) -> PodConfig {
+        let ctr = |name: &str, resources: &Resources| {
+            let json = serde_json::json!({ "name": name, "image": "docker.io/library/busybox:latest" });
+            ContainerConfig { resources: resources.clone(), ..serde_json::from_value(json).unwrap() }
+        };
+        let sandbox = serde_json::json!({ "name": "web", "uid": "uid-web" });
+        PodConfig {
+            restart_policy: RestartPolicy::Always,
+            config: serde_json::from_value(sandbox).unwrap(),
+            containers: limits.iter().map(|(name, resources)| (name.to_string(), ctr(name, resources))).collect(),
+            init_containers: init_limits.iter().enumerate().map(|(i, resources)| ctr(&format!("init-{}", i), resources)).collect(),
+        }
+    }
+
+    #[test]
+    fn resources_translate_to_cri() {
+        assert_eq!(Resources::default().to_cri(), None);
+        let resources = Resources {
+            cpu_millis: Some(250),
+            memory_mb: Some(64),
+            pids: Some(100),
+            hugepages_mb: BTreeMap::from([("2MB".to_owned(), 4)]),
+            ..Default::default()
+        };
+        let cri = resources.to_cri().unwrap();
+        assert_eq!((cri.cpu_period, cri.cpu_quota, cri.cpu_shares), (100_000, 25_000, 0));
+        assert_eq!(cri.memory_limit_in_bytes, 64 * 1024 * 1024);
+        assert_eq!(cri.unified["pids.max"], "100");
+        assert_eq!(cri.hugepage_limits, vec![cri::HugepageLimit { page_size: "2MB".to_owned(), limit: 4 * 1024 * 1024 }]);
+        let tiny = Resources { cpu_millis: Some(1), ..Default::default() };
+        assert_eq!(tiny.to_cri().unwrap().cpu_quota, 1_000);
+    }
+
+    #[test]
+    fn pods_are_limited_to_their_containers() {
+        let limits = |cpu_millis, memory_mb, cpu_shares| Resources { cpu_millis, memory_mb, cpu_shares, ..Default::default() };
+        let podconfig = pod(
+            &[("a", limits(Some(500), Some(100), Some(512))), ("b", limits(Some(250), None, None))],
+            &[limits(Some(1000), Some(50), Some(256))],
+        );
+        let resources = podconfig.resources();
+        // The init container needs more CPU than the rest put together. Not everything has a memory limit.
+        assert_eq!((resources.cpu_millis, resources.memory_mb, resources.cpu_shares), (Some(1000), None, Some(512)));
+        assert_eq!(podconfig.sandbox_config(0).resources, resources);
+        assert!(pod(&[("a", Resources::default())], &[]).sandbox_config(0).resources.is_empty());
+        // Room for more of everything, but not for a limit that's gone.
+        let needs = |memory_mb| Resources { memory_mb, cpu_millis: Some(1000), ..Default::default() };
+        assert!(resources.covers(&needs(Some(64))) && Resources::default().covers(&resources));
+        assert!(!resources.covers(&Resources { cpu_millis: Some(1500), ..Default::default() }));
+        assert!(!Resources { memory_mb: Some(64), ..Default::default() }.covers(&needs(None)));
+    }
+
+    #[test]
+    fn spec_hashes_are_pinned() {
+        // A change here recreates every container and sandbox on the nodes that upgrade.
+        let podconfig = pod(&[("app", Resources { memory_mb: Some(64), ..Default::default() })], &[]);
+        let ctr = &podconfig.containers["app"];
+        assert_eq!(serde_json::to_string(&ContainerConfig { resources: Resources::default(), ..ctr.clone() }).unwrap(),
+            r#"{"name":"app","image":"docker.io/library/busybox:latest"}"#);
+        assert_eq!(ctr.spec_hash(), "a207c48447671aae");
+        assert_eq!(podconfig.config.spec_hash(), "2515452648a78473");
+    }
+
+    #[test]
+    fn limits_can_change_but_not_go_away() {
+        let old = Resources {
+            memory_mb: Some(256),
+            hugepages_mb: BTreeMap::from([("2MB".to_owned(), 64)]),
+            ..Default::default()
+        };
+        assert!(old.can_change_to(&Resources { memory_mb: Some(128), cpu_millis: Some(500), ..old.clone() }));
+        assert!(!old.can_change_to(&Resources { memory_mb: None, ..old.clone() }));
+        assert!(!old.can_change_to(&Resources { hugepages_mb: BTreeMap::new(), ..old.clone() }));
+        assert!(Resources::default().can_change_to(&old));
+    }
+
+    #[test]
+    fn envs_are_resolved() {
+        let podconfig = pod(&[("a", Resources { memory_mb: Some(64), ..Default::default() })], &[]);
+        let config = &podconfig.containers["a"];
+        let values = EnvValues(HashMap::from([("PASSWORD".to_owned(), "hunter2".to_owned())]));
+        let resolve = |value_from| resolve_env(
+            &EnvVar { value_from: Some(value_from), ..EnvVar::new("PASSWORD", "") },
+            config,
+            &podconfig.config,
+            &values,
+            "10.0.0.7",
+        );
+        assert_eq!(resolve(EnvSource::Field { field: PodField::PodUid }), Ok("uid-web".to_owned()));
+        assert_eq!(resolve(EnvSource::Field { field: PodField::PodIp }), Ok("10.0.0.7".to_owned()));
+        assert_eq!(resolve(EnvSource::Resource { resource: ResourceField::MemoryMb }), Ok("64".to_owned()));
+        assert!(resolve(EnvSource::Resource { resource: ResourceField::CpuMillis }).is_err());
+        let secret = EnvSource::Secret { secret: "db".to_owned(), key: "password".to_owned() };
+        assert_eq!(resolve(secret), Ok("hunter2".to_owned()));
+    }
+}
@@ -1,89 +1,659 @@
+use std::collections::{BTreeMap, HashSet};
+use std::time::SystemTime;
 use crate::common::*;
+use crate::prober::{ProbeKind, ProbeResult};
+use crate::runtime::{RestartPolicy, AGENT_LABEL, GENERATION_LABEL, NAME_LABEL, POD_UID_LABEL, SPEC_HASH_LABEL};
+use crate::runtime::{EnvSource, EnvValues, Resources, RESOURCES_ANNOTATION};
+use crate::runtime::{default_grace_period, Hook, DEPENDS_ON_LABEL, GRACE_PERIOD_LABEL, PRE_STOP_ANNOTATION, STOP_SIGNAL_LABEL};
+
+// Containers that keep exiting are restarted after a delay that starts here and doubles with every
+// exit, up to the max. Running for the reset period wipes the slate clean.
+const CRASH_BACKOFF_INITIAL: Duration = Duration::from_secs(10);
+const CRASH_BACKOFF_MAX: Duration = Duration::from_secs(300);
+const CRASH_BACKOFF_RESET: Duration = Duration::from_secs(600);
 
 pub fn to_state(i: i32) -> cri::ContainerState {
-    i.try_into().unwrap()
+    i.try_into().unwrap_or(cri::ContainerState::ContainerUnknown)
+}
+
+/// CRI timestamps are in nanoseconds since the epoch, with 0 meaning never.
+pub fn to_time(nanos: i64) -> Option<SystemTime> {
+    if nanos <= 0 { return None; }
+    Some(SystemTime::UNIX_EPOCH + Duration::from_nanos(nanos as u64))
+}
+
+/// How to stop a container, as recorded on it when it was created.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StopOptions {
+    pub grace_period: Duration,
+    /// If None, the image's stop signal is used.
+    pub signal: Option<String>,
+    pub pre_stop: Option<Hook>,
+}
+
+impl StopOptions {
+    /// Containers we didn't create (or created before we recorded this) get the defaults.
+    pub fn from_metadata(labels: &HashMap<String, String>, annotations: &HashMap<String, String>) -> StopOptions {
+        let grace_period = labels.get(GRACE_PERIOD_LABEL)
+            .and_then(|secs| secs.parse().ok())
+            .unwrap_or_else(default_grace_period);
+        StopOptions {
+            grace_period: Duration::from_secs(grace_period),
+            signal: labels.get(STOP_SIGNAL_LABEL).cloned(),
+            pre_stop: annotations.get(PRE_STOP_ANNOTATION).and_then(|hook| serde_json::from_str(hook).ok()),
+        }
+    }
+}
+
+fn resources(annotations: &HashMap<String, String>) -> Resources {
+    annotations.get(RESOURCES_ANNOTATION)
+        .and_then(|resources| serde_json::from_str(resources).ok())
+        .unwrap_or_default()
+}
+
+fn depends_on(labels: &HashMap<String, String>) -> Vec<Name> {
+    labels.get(DEPENDS_ON_LABEL)
+        .map_or(vec![], |names| names.split(',').filter(|name| !name.is_empty()).map(str::to_owned).collect())
 }
 
 #[derive(Clone, Debug)]
 pub struct CtrStatus {
-    id: CtrId,
-    state: cri::ContainerState,
+    pub id: CtrId,
+    pub state: cri::ContainerState,
+    pub attempt: u32,
+    /// Only known once the container has exited, and only if we saw it exit.
+    pub exit_code: Option<i32>,
+    /// Like the exit code, these are only known once the container has exited.
+    pub started_at: Option<SystemTime>,
+    pub finished_at: Option<SystemTime>,
+    /// The hash of the config the container was created from, if it was labelled with one.
+    pub spec_hash: Option<String>,
+    /// What it was created with. See `State::resources` for what it has now.
+    pub resources: Resources,
+    pub stop: StopOptions,
+    /// The containers it was created depending on, which are stopped after it.
+    pub depends_on: Vec<Name>,
+    /// Whether it's ready for traffic. See `update_readiness`.
+    pub ready: bool,
 }
 
 #[derive(Clone, Debug)]
 pub struct PodStatus {
-    id: PodId,
-    ctrs: HashMap<Name, CtrStatus>,
+    pub id: PodId,
+    /// The generation of the spec the sandbox was created from. Adopted sandboxes are generation 0.
+    pub generation: u64,
+    pub spec_hash: Option<String>,
+    /// What it was created limited to.
+    pub resources: Resources,
+    pub attempt: u32,
+    pub ctrs: HashMap<Name, CtrStatus>,
+    /// Whether all of its service containers are ready.
+    pub ready: bool,
+}
+
+/// What to do with sandboxes and containers on the node that the agent didn't create,
+/// e.g. ones made with ctr or nerdctl, or by another kubelet sharing containerd.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnmanagedPolicy {
+    /// Leave them alone.
+    Ignore,
+    /// Manage them like our own where possible. Anything that isn't in the target gets torn down.
+    Adopt,
+    /// Tear them down.
+    GarbageCollect,
+}
+
+impl std::str::FromStr for UnmanagedPolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<UnmanagedPolicy, String> {
+        match s {
+            "ignore" => Ok(UnmanagedPolicy::Ignore),
+            "adopt" => Ok(UnmanagedPolicy::Adopt),
+            "gc" => Ok(UnmanagedPolicy::GarbageCollect),
+            _ => Err(format!("unknown policy {:?}, expected one of ignore, adopt or gc", s)),
+        }
+    }
+}
+
+/// Containers we don't manage, grouped by the sandbox they live in.
+#[derive(Clone, Debug)]
+pub struct UnmanagedPod {
+    /// Whether the sandbox itself exists and is unmanaged. If not, these are stray containers,
+    /// either in one of our sandboxes or in one that no longer exists.
+    pub sandbox: bool,
+    pub ctrs: HashMap<CtrId, cri::ContainerState>,
+}
+
+/// What we've seen of the containers that have come and gone under one name.
+#[derive(Clone, Debug, Default)]
+pub struct CtrHistory {
+    /// The hash of the config the history is about. It starts over when the config changes.
+    pub spec_hash: Option<String>,
+    pub failures: u32,
+    pub succeeded: bool,
+    /// Exits since the last time a container ran for long enough to reset the crash-loop backoff.
+    pub crashes: u32,
+    /// The exit code of the most recent exit, and when it happened.
+    pub last_exit: Option<(i32, SystemTime)>,
+    // The last container whose exit was counted, so that it's only counted once.
+    counted: Option<CtrId>,
+}
+
+impl CtrHistory {
+    /// How long to wait after the last exit before starting another container.
+    pub fn backoff(&self) -> Duration {
+        if self.crashes == 0 { return Duration::ZERO; }
+        let doublings = (self.crashes - 1).min(31);
+        CRASH_BACKOFF_INITIAL.saturating_mul(1 << doublings).min(CRASH_BACKOFF_MAX)
+    }
+}
+
+/// The highest attempt numbers seen for a pod's sandboxes and containers, so that their
+/// replacements can be given the next one.
+#[derive(Clone, Debug, Default)]
+pub struct Attempts {
+    pub pod: Option<u32>,
+    pub ctrs: HashMap<Name, u32>,
+}
+
+/// When a container may be (re)started.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Restart {
+    Now,
+    /// Once its crash-loop backoff is over.
+    At(SystemTime),
+    Never,
+}
+
+/// How a Job ended up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobOutcome {
+    Succeeded,
+    /// Failed more than its backoff_limit allows.
+    Failed,
+}
+
+/// Who a sandbox or container belongs to, going by its labels.
+enum Owner<T> {
+    /// Ours, or adopted.
+    Managed(T),
+    /// Nobody we know of. What happens to it is up to the UnmanagedPolicy.
+    Unmanaged,
+    /// Another agent's. These are left alone whatever the policy.
+    OtherAgent,
 }
 
 /// The current state of the node.
 pub struct State {
-    pub pods: HashMap<UID, PodStatus>
+    pub pods: HashMap<UID, PodStatus>,
+    pub unmanaged: HashMap<PodId, UnmanagedPod>,
+    pub policy: UnmanagedPolicy,
+    /// Unlike everything else here, these outlive the containers they're about. See `prune`.
+    pub history: HashMap<UID, HashMap<Name, CtrHistory>>,
+    pub attempts: HashMap<UID, Attempts>,
+    // What the prober has said about running containers.
+    /// Failing their liveness or startup probe.
+    pub unhealthy: HashSet<CtrId>,
+    /// Passed their startup probe.
+    pub started: HashSet<CtrId>,
+    /// Passing their readiness probe.
+    pub ready: HashSet<CtrId>,
+    // What's become of updating running containers' resources in place.
+    /// The resources they've been updated to.
+    pub resized: HashMap<CtrId, Resources>,
+    /// The runtime wouldn't update them, so they have to be recreated instead.
+    pub unresizable: HashSet<CtrId>,
+    /// Sandboxes made by agents from before ownership labels, adopted because they're for a pod in
+    /// the target. They're found by listing everything, so see `has_unlabelled`.
+    pub unlabelled: HashSet<PodId>,
+    // The names of the pods in the target, by uid, to recognise those sandboxes by.
+    expected: HashMap<UID, Name>,
+    agent_id: String,
 }
 
 impl State {
-    pub fn new() -> State {
-        State { pods: HashMap::new() }
+    /// `agent_id` is what the RuntimeClient stamps on everything it creates.
+    pub fn new(agent_id: String, policy: UnmanagedPolicy) -> State {
+        State {
+            pods: HashMap::new(),
+            unmanaged: HashMap::new(),
+            policy,
+            history: HashMap::new(),
+            attempts: HashMap::new(),
+            unhealthy: HashSet::new(),
+            started: HashSet::new(),
+            ready: HashSet::new(),
+            resized: HashMap::new(),
+            unresizable: HashSet::new(),
+            unlabelled: HashSet::new(),
+            expected: HashMap::new(),
+            agent_id,
+        }
+    }
+
+    /// Forget about pods that are gone and aren't coming back.
+    pub fn prune(&mut self, target: &Target) {
+        let pods = &self.pods;
+        let keep = |uid: &UID| pods.contains_key(uid) || target.pods.contains_key(uid);
+        self.history.retain(|uid, _| keep(uid));
+        self.attempts.retain(|uid, _| keep(uid));
+        let running = |id: &CtrId| pods.values()
+            .flat_map(|pod| pod.ctrs.values())
+            .any(|ctr| ctr.id == *id && ctr.state == cri::ContainerState::ContainerRunning);
+        self.unhealthy.retain(running);
+        self.started.retain(running);
+        self.ready.retain(running);
+        self.resized.retain(|id, _| running(id));
+        self.unresizable.retain(running);
+    }
+
+    /// Remember which pods the target has, so that sandboxes and containers made for them by an
+    /// agent from before ownership labels are adopted when next ingested.
+    pub fn expect(&mut self, target: &Target) {
+        self.expected = target.pods.iter().map(|(uid, pod)| (uid.clone(), pod.config.name.clone())).collect();
+    }
+
+    /// Whether any sandboxes lack ownership labels, which listing by label wouldn't find.
+    pub fn has_unlabelled(&self) -> bool {
+        !self.unlabelled.is_empty()
+    }
+
+    /// `resized` is None if the runtime refused to update the container's resources.
+    pub fn record_resize(&mut self, id: CtrId, resized: Option<Resources>) {
+        match resized {
+            Some(resources) => { self.resized.insert(id, resources); }
+            None => { self.unresizable.insert(id); }
+        }
+    }
+
+    /// The resources a container has now, whether it was created with them or updated to them since.
+    pub fn resources<'a>(&'a self, ctr: &'a CtrStatus) -> &'a Resources {
+        self.resized.get(&ctr.id).unwrap_or(&ctr.resources)
+    }
+
+    pub fn record_probe(&mut self, result: ProbeResult) {
+        let ProbeResult { id, kind, passing } = result;
+        match kind {
+            ProbeKind::Liveness if passing => { self.unhealthy.remove(&id); }
+            ProbeKind::Readiness if passing => { self.ready.insert(id); }
+            ProbeKind::Startup if passing => { self.started.insert(id); }
+            ProbeKind::Liveness | ProbeKind::Startup => { self.unhealthy.insert(id); }
+            ProbeKind::Readiness => { self.ready.remove(&id); }
+        }
+    }
+
+    /// Work out which containers and pods are ready for traffic. A container is ready while it's
+    /// running, once its PostStart hook has finished and its startup probe has passed and while its
+    /// readiness probe passes, if it has those. Jobs don't count towards a pod's readiness.
+    pub fn update_readiness(&mut self, target: &Target, post_starting: &HashSet<CtrId>) {
+        for (uid, pod) in self.pods.iter_mut() {
+            let podconfig = target.pods.get(uid);
+            for (name, ctr) in pod.ctrs.iter_mut() {
+                let config = podconfig.and_then(|podconfig| podconfig.containers.get(name));
+                ctr.ready = match config {
+                    Some(config) => {
+                        ctr.state == cri::ContainerState::ContainerRunning
+                            && !post_starting.contains(&ctr.id)
+                            && (config.startup_probe.is_none() || self.started.contains(&ctr.id))
+                            && (config.readiness_probe.is_none() || self.ready.contains(&ctr.id))
+                    }
+                    None => false,
+                };
+            }
+            pod.ready = match podconfig {
+                Some(podconfig) => podconfig.containers.iter()
+                    .filter(|(_, config)| config.run_mode == RunMode::Service)
+                    .all(|(name, _)| pod.ctrs.get(name).map_or(false, |ctr| ctr.ready)),
+                None => false,
+            };
+        }
+    }
+
+    /// The attempt number for the pod's next sandbox.
+    pub fn next_pod_attempt(&self, uid: &UID) -> u32 {
+        self.attempts.get(uid).and_then(|a| a.pod).map_or(0, |attempt| attempt + 1)
+    }
+
+    /// The attempt number for the next container under `name`.
+    pub fn next_ctr_attempt(&self, uid: &UID, name: &Name) -> u32 {
+        self.attempts.get(uid).and_then(|a| a.ctrs.get(name)).map_or(0, |attempt| attempt + 1)
+    }
+
+    fn record_attempts(&mut self) {
+        for (uid, pod) in self.pods.iter() {
+            let attempts = self.attempts.entry(uid.clone()).or_default();
+            attempts.pod = Some(attempts.pod.map_or(pod.attempt, |attempt| attempt.max(pod.attempt)));
+            for (name, ctr) in pod.ctrs.iter() {
+                let attempt = attempts.ctrs.entry(name.clone()).or_insert(ctr.attempt);
+                *attempt = (*attempt).max(ctr.attempt);
+            }
+        }
+    }
+
+    /// Whether a Job container is done, one way or the other. Always None for services.
+    pub fn job_outcome(&self, uid: &UID, name: &Name, config: &ContainerConfig) -> Option<JobOutcome> {
+        if config.run_mode != RunMode::Job { return None; }
+        let history = self.history.get(uid)?.get(name)?;
+        if history.spec_hash.as_ref().map_or(false, |hash| *hash != config.spec_hash()) {
+            return None; // It's about an older version of the Job.
+        }
+        match (history.succeeded, history.failures > config.backoff_limit) {
+            (true, _) => Some(JobOutcome::Succeeded),
+            (false, true) => Some(JobOutcome::Failed),
+            (false, false) => None,
+        }
+    }
+
+    /// When the container under `name` may be started, going by how the last one exited.
+    pub fn next_start(&self, uid: &UID, name: &Name, pod: &PodConfig) -> Restart {
+        let config = match pod.container(name) {
+            Some(config) => config,
+            None => { return Restart::Never; }
+        };
+        let init = !pod.containers.contains_key(name);
+        if self.job_outcome(uid, name, config).is_some() {
+            return Restart::Never;
+        }
+        let history = match self.history.get(uid).and_then(|h| h.get(name)) {
+            Some(history) => history,
+            None => { return Restart::Now; }
+        };
+        let (exit_code, finished_at) = match history.last_exit {
+            Some(exit) => exit,
+            None => { return Restart::Now; }
+        };
+        // An init container that succeeded is only run again for a new sandbox, and right away.
+        if init && exit_code == 0 { return Restart::Now; }
+        let restart = match (config.run_mode, pod.restart_policy) {
+            _ if init => pod.restart_policy != RestartPolicy::Never,
+            (RunMode::Job, _) => true, // Not finished, so it failed and has retries left.
+            (RunMode::Service, RestartPolicy::Always) => true,
+            (RunMode::Service, RestartPolicy::OnFailure) => exit_code != 0,
+            (RunMode::Service, RestartPolicy::Never) => false,
+        };
+        if !restart { return Restart::Never; }
+        match history.backoff() {
+            Duration::ZERO => Restart::Now,
+            backoff => Restart::At(finished_at + backoff),
+        }
+    }
+
+    /// Whether the container under `name` has exited 0 and won't be started again.
+    pub fn completed(&self, uid: &UID, name: &Name, pod: &PodConfig) -> bool {
+        let last_exit = self.history.get(uid).and_then(|h| h.get(name)).and_then(|history| history.last_exit);
+        matches!(last_exit, Some((0, _))) && self.next_start(uid, name, pod) == Restart::Never
+    }
+
+    /// Exited containers whose exit codes we don't know yet.
+    pub fn missing_exit_codes(&self) -> Vec<CtrId> {
+        self.pods.values()
+            .flat_map(|pod| pod.ctrs.values())
+            .filter(|ctr| ctr.state == cri::ContainerState::ContainerExited && ctr.exit_code.is_none())
+            .map(|ctr| ctr.id.clone())
+            .collect()
+    }
+
+    /// Fill in how a container exited from its status.
+    pub fn set_exit(&mut self, status: &cri::ContainerStatus) {
+        for pod in self.pods.values_mut() {
+            for ctr in pod.ctrs.values_mut() {
+                if ctr.id == status.id && ctr.state == cri::ContainerState::ContainerExited {
+                    ctr.exit_code = Some(status.exit_code);
+                    ctr.started_at = to_time(status.started_at);
+                    ctr.finished_at = to_time(status.finished_at);
+                }
+            }
+        }
+        self.record_exits();
+    }
+
+    /// Add any exits we haven't seen before to the history.
+    fn record_exits(&mut self) {
+        for (uid, pod) in self.pods.iter() {
+            for (name, ctr) in pod.ctrs.iter() {
+                let exit_code = match ctr.exit_code {
+                    Some(code) => code,
+                    None => { continue; }
+                };
+                let history = self.history.entry(uid.clone()).or_default().entry(name.clone()).or_default();
+                if history.counted.as_ref() == Some(&ctr.id) { continue; }
+                if history.spec_hash != ctr.spec_hash {
+                    *history = CtrHistory { spec_hash: ctr.spec_hash.clone(), ..Default::default() };
+                }
+                history.counted = Some(ctr.id.clone());
+                if exit_code == 0 {
+                    history.succeeded = true;
+                } else {
+                    history.failures += 1;
+                }
+                let ran_for = match (ctr.started_at, ctr.finished_at) {
+                    (Some(started), Some(finished)) => finished.duration_since(started).unwrap_or_default(),
+                    _ => Duration::ZERO,
+                };
+                if ran_for >= CRASH_BACKOFF_RESET {
+                    history.crashes = 0;
+                }
+                history.crashes += 1;
+                // If we don't know when it exited, go by when we found out.
+                history.last_exit = Some((exit_code, ctr.finished_at.unwrap_or_else(SystemTime::now)));
+            }
+        }
+    }
+
+    /// The uid and generation of a sandbox, if it's one we manage.
+    fn pod_owner(&self, labels: &HashMap<String, String>, metadata: &Option<cri::PodSandboxMetadata>) -> Owner<(UID, u64)> {
+        let metadata_uid = metadata.as_ref().map(|m| m.uid.clone()).filter(|uid| !uid.is_empty());
+        match labels.get(AGENT_LABEL) {
+            Some(agent) if *agent == self.agent_id => {
+                let uid = labels.get(POD_UID_LABEL).cloned().or(metadata_uid);
+                let generation = labels.get(GENERATION_LABEL).and_then(|g| g.parse().ok()).unwrap_or(0);
+                uid.map_or(Owner::Unmanaged, |uid| Owner::Managed((uid, generation)))
+            }
+            Some(_) => Owner::OtherAgent,
+            None => match (metadata_uid, self.policy) {
+                (Some(uid), _) if self.expects(labels, metadata) => Owner::Managed((uid, 0)),
+                (Some(uid), UnmanagedPolicy::Adopt) => Owner::Managed((uid, 0)),
+                _ => Owner::Unmanaged,
+            },
+        }
+    }
+
+    // Earlier agents labelled sandboxes with just their name.
+    fn expects(&self, labels: &HashMap<String, String>, metadata: &Option<cri::PodSandboxMetadata>) -> bool {
+        match metadata {
+            Some(metadata) => labels.get(NAME_LABEL) == Some(&metadata.name) && self.expected.get(&metadata.uid) == Some(&metadata.name),
+            None => false,
+        }
+    }
+
+    /// The name of a container, if it's one we manage. Unlabelled containers in unlabelled sandboxes
+    /// we've adopted are ours too, going by their name label.
+    fn ctr_owner(&self, labels: &HashMap<String, String>, metadata: &Option<cri::ContainerMetadata>, pod_id: &PodId) -> Owner<Name> {
+        match labels.get(AGENT_LABEL) {
+            Some(agent) if *agent == self.agent_id => {
+                labels.get(NAME_LABEL).map_or(Owner::Unmanaged, |name| Owner::Managed(name.clone()))
+            }
+            Some(_) => Owner::OtherAgent,
+            None if self.unlabelled.contains(pod_id) && labels.contains_key(NAME_LABEL) => {
+                Owner::Managed(labels[NAME_LABEL].clone())
+            }
+            None => match (metadata, self.policy) {
+                (Some(metadata), UnmanagedPolicy::Adopt) if !metadata.name.is_empty() => Owner::Managed(metadata.name.clone()),
+                _ => Owner::Unmanaged,
+            },
+        }
+    }
+
+    fn add_unmanaged(&mut self, pod_id: &PodId, sandbox: bool, ctr: Option<(CtrId, cri::ContainerState)>) {
+        let pod = self.unmanaged.entry(pod_id.clone())
+            .or_insert_with(|| UnmanagedPod { sandbox, ctrs: HashMap::new() });
+        pod.sandbox |= sandbox;
+        if let Some((id, state)) = ctr {
+            pod.ctrs.insert(id, state);
+        }
     }
 
     pub fn observe(&mut self, message: cri::ContainerEventResponse) {
         let id = message.container_id;
         if message.pod_sandbox_status.is_none() { // Pod Deletion Event
             self.pods.retain(|_, podstatus| { podstatus.id != id });
+            self.unmanaged.remove(&id);
+            self.unlabelled.remove(&id);
             return;
         }
         let sandbox = message.pod_sandbox_status.unwrap();
+        let (uid, generation) = match self.pod_owner(&sandbox.labels, &sandbox.metadata) {
+            Owner::Managed(owned) => owned,
+            Owner::OtherAgent => { return; }
+            Owner::Unmanaged => {
+                // Not ours. The message contains everything, so just replace what we knew.
+                self.unmanaged.remove(&sandbox.id);
+                self.add_unmanaged(&sandbox.id, true, None);
+                for container in message.containers_statuses {
+                    self.add_unmanaged(&sandbox.id, true, Some((container.id, to_state(container.state))));
+                }
+                return;
+            }
+        };
+        if !sandbox.labels.contains_key(AGENT_LABEL) && self.expects(&sandbox.labels, &sandbox.metadata) {
+            self.unlabelled.insert(sandbox.id.clone());
+        }
+        let spec_hash = sandbox.labels.get(SPEC_HASH_LABEL).cloned();
+        let pod_resources = resources(&sandbox.annotations);
+        let attempt = sandbox.metadata.as_ref().map_or(0, |m| m.attempt);
         if &id == &sandbox.id { // Pod Creation event
             self.pods.insert(
-                sandbox.metadata.unwrap().uid.clone(), 
-                PodStatus { id: id.clone(), ctrs: HashMap::new() }
+                uid,
+                PodStatus { id: id.clone(), generation, spec_hash, resources: pod_resources, attempt, ctrs: HashMap::new(), ready: false }
             );
+            self.record_attempts();
             return;
         }
 
         // Just replace the whole pod state. The message contains everything.
+        self.unmanaged.remove(&sandbox.id);
         let mut ctrs = HashMap::new();
         for container in message.containers_statuses {
+            let state = to_state(container.state);
+            let exited = state == cri::ContainerState::ContainerExited;
+            let name = match self.ctr_owner(&container.labels, &container.metadata, &sandbox.id) {
+                Owner::Managed(name) => name,
+                Owner::OtherAgent => { continue; }
+                Owner::Unmanaged => {
+                    self.add_unmanaged(&sandbox.id, false, Some((container.id, state)));
+                    continue;
+                }
+            };
             ctrs.insert(
-                container.metadata.unwrap().name,
-                CtrStatus { id: container.id, state: to_state(container.state) }
+                name,
+                CtrStatus {
+                    id: container.id,
+                    state,
+                    attempt: container.metadata.map_or(0, |m| m.attempt),
+                    exit_code: if exited { Some(container.exit_code) } else { None },
+                    started_at: if exited { to_time(container.started_at) } else { None },
+                    finished_at: if exited { to_time(container.finished_at) } else { None },
+                    spec_hash: container.labels.get(SPEC_HASH_LABEL).cloned(),
+                    resources: resources(&container.annotations),
+                    stop: StopOptions::from_metadata(&container.labels, &container.annotations),
+                    depends_on: depends_on(&container.labels),
+                    ready: false,
+                }
             );
         }
-        let pod = PodStatus { id: sandbox.id.clone(), ctrs };
-        self.pods.entry(sandbox.metadata.unwrap().uid.clone())
+        let pod = PodStatus { id: sandbox.id.clone(), generation, spec_hash, resources: pod_resources, attempt, ctrs, ready: false };
+        self.pods.entry(uid)
             .and_modify(|p| *p = pod.clone())
             .or_insert(pod);
+        self.record_exits();
+        self.record_attempts();
     }
 
     pub fn ingest(&mut self, containers: Vec<cri::Container>, pods: Vec<cri::PodSandbox>) {
+        // Listing containers doesn't tell us how they exited, so hang on to what we already know.
+        let mut exits = HashMap::new();
+        for pod in self.pods.values() {
+            for ctr in pod.ctrs.values() {
+                if ctr.exit_code.is_some() {
+                    exits.insert(ctr.id.clone(), (ctr.exit_code, ctr.started_at, ctr.finished_at));
+                }
+            }
+        }
         self.pods.clear();
+        self.unmanaged.clear();
+        self.unlabelled.clear();
         let mut uids = HashMap::new(); // id -> uid
         for pod in pods {
-            let uid = pod.metadata.unwrap().uid;
-            uids.insert(pod.id.clone(), uid.clone());
-            self.pods.insert(uid, PodStatus { id: pod.id.clone(), ctrs: HashMap::new() });
+            match self.pod_owner(&pod.labels, &pod.metadata) {
+                Owner::Managed((uid, generation)) => {
+                    if !pod.labels.contains_key(AGENT_LABEL) && self.expects(&pod.labels, &pod.metadata) {
+                        self.unlabelled.insert(pod.id.clone());
+                    }
+                    uids.insert(pod.id.clone(), uid.clone());
+                    let spec_hash = pod.labels.get(SPEC_HASH_LABEL).cloned();
+                    let resources = resources(&pod.annotations);
+                    let attempt = pod.metadata.as_ref().map_or(0, |m| m.attempt);
+                    self.pods.insert(uid, PodStatus { id: pod.id.clone(), generation, spec_hash, resources, attempt, ctrs: HashMap::new(), ready: false });
+                }
+                Owner::Unmanaged => self.add_unmanaged(&pod.id, true, None),
+                Owner::OtherAgent => {}
+            }
         }
         for ctr in containers {
-            let name = ctr.labels.get("name").unwrap().clone();
-            let pod_uid = uids.get(&ctr.pod_sandbox_id).unwrap();
-            let pod = self.pods.get_mut(pod_uid).unwrap();
+            let state = to_state(ctr.state);
+            let name = match self.ctr_owner(&ctr.labels, &ctr.metadata, &ctr.pod_sandbox_id) {
+                Owner::Managed(name) => Some(name),
+                Owner::Unmanaged => None,
+                Owner::OtherAgent => { continue; }
+            };
+            let pod = uids.get(&ctr.pod_sandbox_id).and_then(|uid| self.pods.get_mut(uid));
+            let (name, pod) = match (name, pod) {
+                (Some(name), Some(pod)) => (name, pod),
+                _ => {
+                    // Either it's in a sandbox we don't manage, or it's a stranger in one of ours.
+                    let sandbox = self.unmanaged.get(&ctr.pod_sandbox_id).map_or(false, |pod| pod.sandbox);
+                    self.add_unmanaged(&ctr.pod_sandbox_id, sandbox, Some((ctr.id, state)));
+                    continue;
+                }
+            };
+            let (exit_code, started_at, finished_at) = match state {
+                cri::ContainerState::ContainerExited => exits.get(&ctr.id).copied().unwrap_or_default(),
+                _ => Default::default(),
+            };
             pod.ctrs.insert(name, CtrStatus {
+                attempt: ctr.metadata.map_or(0, |m| m.attempt),
+                spec_hash: ctr.labels.get(SPEC_HASH_LABEL).cloned(),
+                resources: resources(&ctr.annotations),
+                stop: StopOptions::from_metadata(&ctr.labels, &ctr.annotations),
+                depends_on: depends_on(&ctr.labels),
+                ready: false,
                 id: ctr.id,
-                state: to_state(ctr.state),
+                state,
+                exit_code,
+                started_at,
+                finished_at,
             });
         }
+        self.record_exits();
+        self.record_attempts();
     }
 }
 
+/// Files for pods to mount, by file name.
+pub type Files = BTreeMap<String, String>;
+
 /// The intended state of the node.
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub struct Target {
-    pub pods: HashMap<UID, PodConfig>
+    pub pods: HashMap<UID, PodConfig>,
+    /// Files that pods' config and secret volumes are filled with, by name.
+    pub configs: HashMap<Name, Files>,
+    pub secrets: HashMap<Name, Files>,
 }
 
 impl Target {
     pub fn new() -> Target {
-        Target { pods: HashMap::new() }
+        Target { pods: HashMap::new(), configs: HashMap::new(), secrets: HashMap::new() }
     }
 }
 
@@ -96,44 +666,268 @@ pub enum PodStep {
 
 #[derive(Clone, Debug)]
 pub enum ContainerStep {
-    CreateCtr(PodId, ContainerConfig, SandBoxConfig),
-    StartCtr(CtrId),
-    StopCtr(CtrId),
+    /// Along with the values its env vars take from the node's configs and secrets.
+    CreateCtr(PodId, ContainerConfig, SandBoxConfig, EnvValues),
+    /// Along with its PostStart hook, if it has one.
+    StartCtr(CtrId, Option<Hook>),
+    StopCtr(CtrId, StopOptions),
+    /// Change a running container's resources without recreating it.
+    UpdateResources(CtrId, Resources),
     DeleteCtr(CtrId),
     WaitCtr(CtrId),
 }
 
 /// A tree of steps that will get us from State to Target
 pub struct Plan {
-    pub pods: HashMap<UID, PodStep>
+    pub pods: HashMap<UID, PodStep>,
+    /// Steps for cleaning up sandboxes and containers we don't manage, by sandbox id.
+    pub unmanaged: HashMap<PodId, PodStep>,
+    /// When the next container that's being held back may be started, so that we can plan again then.
+    pub wake_at: Option<SystemTime>,
+}
+
+/// Whether something was created from a different config than the one given. Anything created
+/// before we started labelling, or adopted, is assumed to be up to date.
+fn is_stale(spec_hash: &Option<String>, current: impl FnOnce() -> String) -> bool {
+    spec_hash.as_ref().map_or(false, |hash| *hash != current())
 }
 
-pub fn diff(target: &Target, state: &State) -> Plan {
+/// Whether a created or running container's resources are out of date. Ones that have exited get
+/// the current resources when they're replaced anyway.
+fn resources_stale(state: &State, ctr: &CtrStatus, ctrconfig: &ContainerConfig) -> bool {
+    let live = matches!(ctr.state, cri::ContainerState::ContainerCreated | cri::ContainerState::ContainerRunning);
+    live && ctr.spec_hash.is_some() && *state.resources(ctr) != ctrconfig.resources
+}
+
+/// Whether a running container's limits can be changed to the target's without recreating it.
+/// Ones an env var comes from can't, since the env var would be left behind.
+fn resizable(state: &State, ctr: &CtrStatus, ctrconfig: &ContainerConfig) -> bool {
+    let in_env = ctrconfig.envs.iter().any(|env| matches!(env.value_from, Some(EnvSource::Resource { .. })));
+    ctr.state == cri::ContainerState::ContainerRunning
+        && !in_env
+        && !state.unresizable.contains(&ctr.id)
+        && state.resources(ctr).can_change_to(&ctrconfig.resources)
+}
+
+/// What a pod's containers need of its sandbox. Running containers whose limits are changed in
+/// place count for what they were created with, since the sandbox's limits can't be changed, so
+/// that only the runtime refusing to change a container makes the pod too small for it.
+fn sandbox_needs(state: &State, pod: &PodStatus, podconfig: &PodConfig) -> Resources {
+    let mut podconfig = podconfig.clone();
+    for (name, ctrconfig) in podconfig.containers.iter_mut() {
+        match pod.ctrs.get(name) {
+            Some(ctr) if ctr.spec_hash.is_some() && resizable(state, ctr, ctrconfig) => {
+                ctrconfig.resources = ctr.resources.clone();
+            }
+            _ => {}
+        }
+    }
+    podconfig.resources()
+}
+
+/// How to get rid of a container, whatever state it's in.
+fn remove_ctr(ctr: &CtrStatus) -> ContainerStep {
+    use ContainerStep::*;
+    use cri::ContainerState as CS;
+    match ctr {
+        &CtrStatus { ref id, state: CS::ContainerCreated, .. } => DeleteCtr(id.clone()),
+        &CtrStatus { ref id, state: CS::ContainerRunning, ref stop, .. } => StopCtr(id.clone(), stop.clone()),
+        &CtrStatus { ref id, state: CS::ContainerExited, .. } => DeleteCtr(id.clone()),
+        &CtrStatus { ref id, state: CS::ContainerUnknown, .. } => WaitCtr(id.clone()),
+    }
+}
+
+/// How to get rid of a pod: stop all of its containers first, then remove the sandbox.
+/// Containers that others depend on are stopped once those others have.
+fn remove_pod(pod: &PodStatus) -> PodStep {
+    use cri::ContainerState as CS;
+    let running: Vec<(&Name, &CtrStatus)> = pod.ctrs.iter()
+        .filter(|(_, ctr)| ctr.state == CS::ContainerRunning)
+        .collect();
+    let needed: HashSet<&Name> = running.iter()
+        .flat_map(|(_, ctr)| ctr.depends_on.iter())
+        .collect();
+    let mut steps: HashMap<Name, ContainerStep> = running.iter()
+        .filter(|(name, _)| !needed.contains(name))
+        .map(|(name, ctr)| ((*name).clone(), ContainerStep::StopCtr(ctr.id.clone(), ctr.stop.clone())))
+        .collect();
+    // Only a cycle would hold everything back. Manifests can't have one, but labels can be anything.
+    if steps.is_empty() {
+        steps = running.iter()
+            .map(|(name, ctr)| ((*name).clone(), ContainerStep::StopCtr(ctr.id.clone(), ctr.stop.clone())))
+            .collect();
+    }
+    if steps.len() > 0 {
+        PodStep::ChangePod(steps)
+    } else {
+        PodStep::DeletePod(pod.id.clone())
+    }
+}
+
+/// Containers that others depend on are stopped once those others have, as in `remove_pod`. So
+/// stopping one stops its running dependents first and holds its own stop back until they have, and
+/// nothing is started against a container that's on its way out.
+fn order_stops(pod: &PodStatus, steps: &mut HashMap<Name, ContainerStep>) {
+    use cri::ContainerState as CS;
+    let mut held: HashSet<Name> = HashSet::new();
+    loop {
+        let stopping: Vec<Name> = steps.iter()
+            .filter(|(name, step)| matches!(step, ContainerStep::StopCtr(..)) && !held.contains(*name))
+            .map(|(name, _)| name.clone())
+            .collect();
+        let mut changed = false;
+        for name in stopping {
+            // Skipping held ones means a cycle, which labels can have, still gets stopped in some order.
+            let dependents: Vec<(&Name, &CtrStatus)> = pod.ctrs.iter()
+                .filter(|(other, ctr)| ctr.state == CS::ContainerRunning && ctr.depends_on.contains(&name) && !held.contains(*other))
+                .collect();
+            if dependents.is_empty() { continue; }
+            for (other, ctr) in dependents {
+                if !matches!(steps.get(other), Some(ContainerStep::StopCtr(..))) {
+                    steps.insert(other.clone(), ContainerStep::StopCtr(ctr.id.clone(), ctr.stop.clone()));
+                }
+            }
+            steps.remove(&name);
+            held.insert(name);
+            changed = true;
+        }
+        if !changed { break; }
+    }
+    let leaving: HashSet<Name> = steps.iter()
+        .filter(|(_, step)| matches!(step, ContainerStep::StopCtr(..)))
+        .map(|(name, _)| name.clone())
+        .chain(held)
+        .collect();
+    steps.retain(|name, step| {
+        !matches!(step, ContainerStep::StartCtr(..))
+            || !pod.ctrs.get(name).map_or(false, |ctr| ctr.depends_on.iter().any(|dep| leaving.contains(dep)))
+    });
+}
+
+/// Whether a container that others depend on is ready, or for a Job, has succeeded.
+fn dependency_met(state: &State, uid: &UID, pod: &PodStatus, podconfig: &PodConfig, name: &Name) -> bool {
+    let succeeded = podconfig.containers.get(name)
+        .map_or(false, |config| state.job_outcome(uid, name, config) == Some(JobOutcome::Succeeded));
+    succeeded || pod.ctrs.get(name).map_or(false, |ctr| ctr.ready)
+}
+
+/// What a container's env vars that refer to the node's configs and secrets are set to. Ones whose
+/// key is missing are left out, for the container's creation to fail on.
+fn env_values(ctrconfig: &ContainerConfig, target: &Target) -> EnvValues {
+    let values = ctrconfig.envs.iter()
+        .filter_map(|env| {
+            let value = match env.value_from {
+                Some(EnvSource::Config { ref config, ref key }) => target.configs.get(config)?.get(key)?,
+                Some(EnvSource::Secret { ref secret, ref key }) => target.secrets.get(secret)?.get(key)?,
+                _ => { return None; }
+            };
+            Some((env.name.clone(), value.clone()))
+        })
+        .collect();
+    EnvValues(values)
+}
+
+/// Whether a container can't be started yet, or at all. Keeps track of the earliest time one can.
+fn held_back(start: Restart, now: SystemTime, wake_at: &mut Option<SystemTime>) -> bool {
+    match start {
+        Restart::Now => false,
+        Restart::At(time) if time <= now => false,
+        Restart::At(time) => {
+            *wake_at = Some(wake_at.map_or(time, |wake_at| wake_at.min(time)));
+            true
+        }
+        Restart::Never => true,
+    }
+}
+
+pub fn diff(target: &Target, state: &State, now: SystemTime) -> Plan {
     use PodStep::*;
     use ContainerStep::*;
     use cri::ContainerState as CS;
-    let mut plan = Plan { pods: HashMap::new() };
+    let mut plan = Plan { pods: HashMap::new(), unmanaged: HashMap::new(), wake_at: None };
 
     // Check that every pod in target exists in state
     for (uid, podconfig) in target.pods.iter() {
         if !state.pods.contains_key(uid) {
+            let config = podconfig.sandbox_config(state.next_pod_attempt(uid));
             plan.pods.insert(
                 uid.clone(),
-                CreatePod (podconfig.config.clone())
+                CreatePod (config)
             );
             continue;
         }
         let pod = state.pods.get(uid).unwrap();
+        // The sandbox itself can't be changed, so it has to be recreated along with everything in it.
+        // So it does when its containers need more than it was limited to.
+        let outgrown = !pod.resources.covers(&podconfig.resources()) && !pod.resources.covers(&sandbox_needs(state, pod, podconfig));
+        if is_stale(&pod.spec_hash, || podconfig.config.spec_hash()) || outgrown {
+            plan.pods.insert(uid.clone(), remove_pod(pod));
+            continue;
+        }
         let mut steps = HashMap::new();
-        // Check that every pod's container exists and is running
-        // TODO: This assumes that every container's desired state is RUNNING. Eventually we will support Jobs, whose desired state is EXITED with status code 0.
-        for (name, ctrconfig) in podconfig.containers.iter() {
+        // Init containers go first, one at a time. Only the ones that have exited 0 in this sandbox are done.
+        for ctrconfig in podconfig.init_containers.iter() {
+            let name = &ctrconfig.name;
+            let step = match pod.ctrs.get(name) {
+                Some(&CtrStatus{ state: CS::ContainerExited, exit_code: Some(0), .. }) => { continue; }
+                Some(&CtrStatus{ state: CS::ContainerExited, exit_code: None, .. }) => None,
+                None | Some(&CtrStatus{ state: CS::ContainerExited, .. })
+                    if held_back(state.next_start(uid, name, podconfig), now, &mut plan.wake_at) => None,
+                Some(ctr) if is_stale(&ctr.spec_hash, || ctrconfig.spec_hash()) => Some(remove_ctr(ctr)),
+                Some(ctr) if resources_stale(state, ctr, ctrconfig) => Some(remove_ctr(ctr)),
+                None => Some(CreateCtr(
+                    pod.id.clone(),
+                    ContainerConfig { attempt: state.next_ctr_attempt(uid, name), ..ctrconfig.clone() },
+                    podconfig.sandbox_config(pod.attempt),
+                    env_values(ctrconfig, target),
+                )),
+                Some(&CtrStatus{ ref id, state: CS::ContainerCreated, .. }) => Some(StartCtr(id.clone(), ctrconfig.lifecycle.post_start.clone())),
+                Some(&CtrStatus{ state: CS::ContainerRunning, .. }) => None,
+                Some(&CtrStatus{ ref id, state: CS::ContainerExited, .. }) => Some(DeleteCtr(id.clone())),
+                Some(&CtrStatus{ ref id, state: CS::ContainerUnknown, .. }) => Some(WaitCtr(id.clone())),
+            };
+            if let Some(step) = step {
+                steps.insert(name.clone(), step);
+            }
+            break;
+        }
+        let initialized = podconfig.init_containers.iter()
+            .all(|ctrconfig| matches!(pod.ctrs.get(&ctrconfig.name), Some(&CtrStatus{ state: CS::ContainerExited, exit_code: Some(0), .. })));
+        let main_completed = podconfig.containers.iter()
+            .filter(|(_, ctrconfig)| !ctrconfig.sidecar)
+            .all(|(name, _)| state.completed(uid, name, podconfig));
+        // Check that every pod's container exists and is running, or for Jobs, has run to completion.
+        for (name, ctrconfig) in podconfig.containers.iter().filter(|_| initialized) {
             let step = match pod.ctrs.get(name) {
-                None => CreateCtr(pod.id.clone(), ctrconfig.clone(), podconfig.config.clone()),
-                Some(&CtrStatus{ ref id, state: CS::ContainerCreated }) => StartCtr(id.clone()),
+                // Their work is done, so they're stopped for good.
+                Some(ctr) if ctrconfig.sidecar && main_completed && ctr.state == CS::ContainerRunning => remove_ctr(ctr),
+                _ if ctrconfig.sidecar && main_completed => { continue; }
+                // Once it's gone it gets created again from the new config.
+                Some(ctr) if is_stale(&ctr.spec_hash, || ctrconfig.spec_hash()) => remove_ctr(ctr),
+                // Running containers' limits are changed in place if the runtime will, and otherwise
+                // they're recreated like for any other change.
+                Some(ctr) if resources_stale(state, ctr, ctrconfig) => {
+                    if resizable(state, ctr, ctrconfig) { UpdateResources(ctr.id.clone(), ctrconfig.resources.clone()) } else { remove_ctr(ctr) }
+                }
+                // Can't tell whether to restart it yet.
+                Some(&CtrStatus{ state: CS::ContainerExited, exit_code: None, .. }) => { continue; }
+                // Exited containers are kept around (logs and all) until they're due to be replaced.
+                None | Some(&CtrStatus{ state: CS::ContainerExited, .. })
+                    if held_back(state.next_start(uid, name, podconfig), now, &mut plan.wake_at) => { continue; }
+                None => CreateCtr(
+                    pod.id.clone(),
+                    ContainerConfig { attempt: state.next_ctr_attempt(uid, name), ..ctrconfig.clone() },
+                    podconfig.sandbox_config(pod.attempt),
+                    env_values(ctrconfig, target),
+                ),
+                Some(&CtrStatus{ state: CS::ContainerCreated, .. })
+                    if !ctrconfig.depends_on.iter().all(|dep| dependency_met(state, uid, pod, podconfig, dep)) => { continue; }
+                Some(&CtrStatus{ ref id, state: CS::ContainerCreated, .. }) => StartCtr(id.clone(), ctrconfig.lifecycle.post_start.clone()),
+                // Restarted like any other crash once it's stopped.
+                Some(ctr) if state.unhealthy.contains(&ctr.id) => remove_ctr(ctr),
                 Some(&CtrStatus{ state: CS::ContainerRunning, .. }) => { continue; }
-                Some(&CtrStatus{ ref id, state: CS::ContainerExited }) => DeleteCtr(id.clone()),
-                Some(&CtrStatus{ ref id, state: CS::ContainerUnknown }) => WaitCtr(id.clone()),
+                Some(&CtrStatus{ ref id, state: CS::ContainerExited, .. }) => DeleteCtr(id.clone()),
+                Some(&CtrStatus{ ref id, state: CS::ContainerUnknown, .. }) => WaitCtr(id.clone()),
             };
             steps.insert(name.clone(), step);
         }
@@ -148,34 +942,15 @@ pub fn diff(target: &Target, state: &State) -> Plan {
     // Check that every pod that is running is meant to be
     for (uid, podstatus) in state.pods.iter() {
         if !target.pods.contains_key(uid) {
-            // Check that every container is stopped first.
-            let mut steps = HashMap::new();
-            for (name, ctrstatus) in podstatus.ctrs.iter() {
-                let step = match ctrstatus {
-                    &CtrStatus { ref id, state: CS::ContainerRunning } => StopCtr(id.clone()),
-                    _ => { continue; }
-                };
-                steps.insert(name.clone(), step);
-            }
-            if steps.len() > 0 {
-                plan.pods.insert(uid.clone(), ChangePod(steps));
-            } else {
-                plan.pods.insert(uid.clone(), DeletePod(podstatus.id.clone()));
-            }
+            plan.pods.insert(uid.clone(), remove_pod(podstatus));
             continue;
         }
         let target_pod = target.pods.get(uid).unwrap();
         // Pod exists, but we have to be sure we're not running extra containers.
         let mut steps = HashMap::new();
         for (name, ctrstatus) in podstatus.ctrs.iter() {
-            if !target_pod.containers.contains_key(name) {
-                let step = match ctrstatus {
-                    &CtrStatus { ref id, state: CS::ContainerCreated } => DeleteCtr(id.clone()),
-                    &CtrStatus { ref id, state: CS::ContainerRunning } => StopCtr(id.clone()),
-                    &CtrStatus { ref id, state: CS::ContainerExited } => DeleteCtr(id.clone()),
-                    &CtrStatus { ref id, state: CS::ContainerUnknown } => WaitCtr(id.clone()),
-                };
-                steps.insert(name.clone(), step);
+            if target_pod.container(name).is_none() {
+                steps.insert(name.clone(), remove_ctr(ctrstatus));
             }
         }
         if steps.len() > 0 {
@@ -188,7 +963,38 @@ pub fn diff(target: &Target, state: &State) -> Plan {
                         first_steps.insert(name, step);
                     }
                 }
-                _ => unreachable!("Tried to add or delete containers to a pod marked for deletion.")
+                // Being torn down anyway.
+                Some(&mut DeletePod(_)) => {}
+                _ => unreachable!("Tried to add or delete containers to a pod marked for creation.")
+            }
+        }
+    }
+
+    for (uid, step) in plan.pods.iter_mut() {
+        if let (ChangePod(steps), Some(pod)) = (step, state.pods.get(uid)) {
+            order_stops(pod, steps);
+        }
+    }
+    plan.pods.retain(|_, step| !matches!(step, ChangePod(steps) if steps.is_empty()));
+
+    // Clean up after whoever else is using this containerd, if we've been asked to.
+    if state.policy == UnmanagedPolicy::GarbageCollect {
+        for (pod_id, pod) in state.unmanaged.iter() {
+            let mut steps = HashMap::new();
+            for (id, ctr_state) in pod.ctrs.iter() {
+                let step = match ctr_state {
+                    CS::ContainerRunning => StopCtr(id.clone(), StopOptions::from_metadata(&HashMap::new(), &HashMap::new())),
+                    CS::ContainerUnknown => WaitCtr(id.clone()),
+                    // Removing the sandbox takes the rest of its containers with it.
+                    _ if pod.sandbox => { continue; }
+                    CS::ContainerCreated | CS::ContainerExited => DeleteCtr(id.clone()),
+                };
+                steps.insert(id.clone(), step);
+            }
+            if steps.len() > 0 {
+                plan.unmanaged.insert(pod_id.clone(), ChangePod(steps));
+            } else if pod.sandbox {
+                plan.unmanaged.insert(pod_id.clone(), DeletePod(pod_id.clone()));
             }
         }
     }
@@ -218,8 +1024,17 @@ impl std::fmt::Debug for State {
         for (uid, pod) in self.pods.iter() {
             writeln!(f, "    {}: {{", uid)?;
             writeln!(f, "        <id>: {}", pod.id)?;
+            writeln!(f, "        <generation>: {}", pod.generation)?;
+            writeln!(f, "        <attempt>: {}", pod.attempt)?;
             for (name, ctr) in pod.ctrs.iter() {
-                writeln!(f, "        {}: ({}, {:?})", name, ctr.id, ctr.state)?;
+                writeln!(f, "        {}: ({}, {:?}, attempt {})", name, ctr.id, ctr.state, ctr.attempt)?;
+            }
+            writeln!(f, "    }}")?;
+        }
+        for (id, pod) in self.unmanaged.iter() {
+            writeln!(f, "    <unmanaged> {}: {{", id)?;
+            for (ctr_id, state) in pod.ctrs.iter() {
+                writeln!(f, "        {}: {:?}", ctr_id, state)?;
             }
             writeln!(f, "    }}")?;
         }
@@ -233,25 +1048,31 @@ impl std::fmt::Debug for Plan {
         use PodStep::*;
         use ContainerStep::*;
         writeln!(f, "Plan: {{")?;
-        for (uid, pod) in self.pods.iter() {
+        if let Some(wake_at) = self.wake_at {
+            writeln!(f, "    <wake_at>: {:?}", wake_at)?;
+        }
+        for (uid, pod) in self.pods.iter().chain(self.unmanaged.iter()) {
             write!(f, "    {}:", uid)?;
             match pod {
                 &CreatePod(ref config) => {
-                    writeln!(f, "CREATEPOD {}", &config.name)?;
+                    writeln!(f, "CREATEPOD {} (attempt {})", &config.name, config.attempt)?;
                 }
                 &ChangePod(ref ctrs) => {
                     writeln!(f, "CHANGEPOD {{")?;
                     for (name, ctr) in ctrs.iter() {
                         match ctr.clone() {
                             CreateCtr(_id, config, ..) => {
-                                writeln!(f, "        {}: CREATE {}", name, config.image)?;
+                                writeln!(f, "        {}: CREATE {} (attempt {})", name, config.image, config.attempt)?;
                             }
-                            StartCtr(id) => {
+                            StartCtr(id, _) => {
                                 writeln!(f, "        {}: START {}", name, id)?;
                             }
-                            StopCtr(id) => {
+                            StopCtr(id, _) => {
                                 writeln!(f, "        {}: STOP {}", name, id)?;   
                             }
+                            UpdateResources(id, _) => {
+                                writeln!(f, "        {}: UPDATE {}", name, id)?;
+                            }
                             DeleteCtr(id) => {
                                 writeln!(f, "        {}: DELETE {}", name, id)?;
                             }
@@ -274,6 +1095,899 @@ impl std::fmt::Debug for Plan {
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
+    use super::*;
+    use cri::ContainerState as CS;
+    use crate::runtime::{Check, EnvVar, Probe};
+
+    pub(crate) const AGENT: &'static str = "agent-a";
+
+    pub(crate) fn new_state(policy: UnmanagedPolicy) -> State {
+        State::new(AGENT.to_owned(), policy)
+    }
+
+    pub(crate) fn sandbox(id: &str, uid: &str) -> cri::PodSandbox {
+        cri::PodSandbox {
+            id: id.to_owned(),
+            metadata: Some(cri::PodSandboxMetadata { name: "web".to_owned(), uid: uid.to_owned(), ..Default::default() }),
+            labels: HashMap::from([
+                (NAME_LABEL.to_owned(), "web".to_owned()),
+                (AGENT_LABEL.to_owned(), AGENT.to_owned()),
+                (POD_UID_LABEL.to_owned(), uid.to_owned()),
+                (GENERATION_LABEL.to_owned(), "1".to_owned()),
+            ]),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn container(id: &str, pod_id: &str, name: &str, state: CS, attempt: u32) -> cri::Container {
+        cri::Container {
+            id: id.to_owned(),
+            pod_sandbox_id: pod_id.to_owned(),
+            metadata: Some(cri::ContainerMetadata { name: name.to_owned(), attempt }),
+            labels: HashMap::from([
+                (NAME_LABEL.to_owned(), name.to_owned()),
+                (AGENT_LABEL.to_owned(), AGENT.to_owned()),
+            ]),
+            state: state.into(),
+            ..Default::default()
+        }
+    }
+
+    // How a container exited, long enough ago that any backoff is over.
+    pub(crate) fn exited(id: &str, exit_code: i32) -> cri::ContainerStatus {
+        cri::ContainerStatus {
+            id: id.to_owned(),
+            state: CS::ContainerExited.into(),
+            exit_code,
+            started_at: 1_000_000_000_000,
+            finished_at: 1_001_000_000_000,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn ctr_config(name: &str) -> ContainerConfig {
+        ContainerConfig {
+            name: name.to_owned(),
+            image: "docker.io/library/alpine:3.21.2".to_owned(),
+            command: String::new(),
+            args: vec![],
+            working_dir: String::new(),
+            envs: vec![],
+            volume_mounts: vec![],
+            resources: Default::default(),
+            privileged: false,
+            run_mode: RunMode::Service,
+            sidecar: false,
+            backoff_limit: 6,
+            grace_period: 30,
+            stop_signal: None,
+            lifecycle: Default::default(),
+            depends_on: vec![],
+            liveness_probe: None,
+            readiness_probe: None,
+            startup_probe: None,
+            attempt: 0,
+        }
+    }
+
+    pub(crate) fn pod_config(uid: &str, ctrs: &[&str]) -> PodConfig {
+        PodConfig {
+            restart_policy: RestartPolicy::Always,
+            init_containers: vec![],
+            config: SandBoxConfig { name: "web".to_owned(), uid: uid.to_owned(), resources: Default::default(), attempt: 0, namespace: "default".to_owned(), generation: 1, volumes: vec![] },
+            containers: ctrs.iter().map(|name| (name.to_string(), ctr_config(name))).collect(),
+        }
+    }
+
+    // What nerdctl or another kubelet would leave lying around: no labels of ours.
+    fn foreign_sandbox(id: &str, uid: &str) -> cri::PodSandbox {
+        cri::PodSandbox { labels: HashMap::new(), ..sandbox(id, uid) }
+    }
+
+    fn foreign_container(id: &str, pod_id: &str, name: &str, state: CS) -> cri::Container {
+        cri::Container { labels: HashMap::new(), ..container(id, pod_id, name, state, 0) }
+    }
+
+    // Another hyphae agent sharing the same containerd.
+    fn relabel(labels: &mut HashMap<String, String>, agent: &str) {
+        labels.insert(AGENT_LABEL.to_owned(), agent.to_owned());
+    }
+
+    fn ingested(policy: UnmanagedPolicy) -> State {
+        let mut state = new_state(policy);
+        state.ingest(
+            vec![
+                container("c1", "p1", "app", CS::ContainerRunning, 0),
+                foreign_container("c2", "p1", "intruder", CS::ContainerRunning),
+                foreign_container("c3", "p2", "other", CS::ContainerRunning),
+                foreign_container("c4", "p2", "other-done", CS::ContainerExited),
+                foreign_container("c5", "gone", "orphan", CS::ContainerExited),
+            ],
+            vec![
+                sandbox("p1", "uid-1"),
+                foreign_sandbox("p2", "uid-2"),
+                cri::PodSandbox { id: "p3".to_owned(), ..Default::default() },
+            ],
+        );
+        state
+    }
+
+    #[test]
+    fn ingest_sets_aside_unmanaged_objects() {
+        let state = ingested(UnmanagedPolicy::Ignore);
+        assert_eq!(state.pods.len(), 1);
+        let pod = &state.pods["uid-1"];
+        assert_eq!(pod.ctrs.keys().collect::<Vec<_>>(), vec!["app"]);
+
+        // A stranger in our own sandbox.
+        assert!(!state.unmanaged["p1"].sandbox);
+        assert!(state.unmanaged["p1"].ctrs.contains_key("c2"));
+        // Somebody else's sandbox.
+        assert!(state.unmanaged["p2"].sandbox);
+        assert_eq!(state.unmanaged["p2"].ctrs.len(), 2);
+        // A sandbox without metadata.
+        assert!(state.unmanaged["p3"].sandbox);
+        // A container whose sandbox is gone.
+        assert!(!state.unmanaged["gone"].sandbox);
+    }
+
+    #[test]
+    fn ignore_leaves_unmanaged_objects_alone() {
+        let state = ingested(UnmanagedPolicy::Ignore);
+        let mut target = Target::new();
+        target.pods.insert("uid-1".to_owned(), pod_config("uid-1", &["app"]));
+        let plan = diff(&target, &state, SystemTime::now());
+        assert!(plan.pods.is_empty());
+        assert!(plan.unmanaged.is_empty());
+    }
+
+    #[test]
+    fn adopt_manages_foreign_objects_with_metadata() {
+        let state = ingested(UnmanagedPolicy::Adopt);
+        assert_eq!(state.pods["uid-1"].ctrs.len(), 2);
+        assert!(state.pods["uid-1"].ctrs.contains_key("intruder"));
+        assert_eq!(state.pods["uid-2"].id, "p2");
+        assert_eq!(state.pods["uid-2"].ctrs.len(), 2);
+        // Without metadata there's nothing to adopt it as.
+        assert!(state.unmanaged.contains_key("p3"));
+        assert!(state.unmanaged.contains_key("gone"));
+
+        // Adopted pods that aren't in the target get torn down like any of ours.
+        let plan = diff(&Target::new(), &state, SystemTime::now());
+        assert!(matches!(plan.pods.get("uid-2"), Some(PodStep::ChangePod(steps)) if steps.contains_key("other")));
+        assert!(plan.unmanaged.is_empty());
+    }
+
+    #[test]
+    fn garbage_collect_tears_down_unmanaged_objects() {
+        let state = ingested(UnmanagedPolicy::GarbageCollect);
+        let plan = diff(&Target::new(), &state, SystemTime::now());
+
+        match plan.unmanaged.get("p1") {
+            Some(PodStep::ChangePod(steps)) => {
+                assert!(matches!(steps.get("c2"), Some(ContainerStep::StopCtr(id, _)) if id == "c2"));
+            }
+            _ => panic!("expected the stranger in p1 to be stopped"),
+        }
+        match plan.unmanaged.get("p2") {
+            Some(PodStep::ChangePod(steps)) => {
+                assert!(matches!(steps.get("c3"), Some(ContainerStep::StopCtr(..))));
+                assert!(!steps.contains_key("c4"));
+            }
+            _ => panic!("expected p2's running container to be stopped first"),
+        }
+        assert!(matches!(plan.unmanaged.get("p3"), Some(PodStep::DeletePod(id)) if id == "p3"));
+        match plan.unmanaged.get("gone") {
+            Some(PodStep::ChangePod(steps)) => assert!(matches!(steps.get("c5"), Some(ContainerStep::DeleteCtr(_)))),
+            _ => panic!("expected the orphan to be deleted"),
+        }
+    }
+
+    #[test]
+    fn observe_tolerates_foreign_events() {
+        let mut state = new_state(UnmanagedPolicy::Ignore);
+        state.observe(cri::ContainerEventResponse {
+            container_id: "c9".to_owned(),
+            pod_sandbox_status: Some(cri::PodSandboxStatus { id: "p9".to_owned(), ..Default::default() }),
+            containers_statuses: vec![cri::ContainerStatus { id: "c9".to_owned(), ..Default::default() }],
+            ..Default::default()
+        });
+        assert!(state.pods.is_empty());
+        assert!(state.unmanaged["p9"].ctrs.contains_key("c9"));
+
+        // A container without metadata in one of our sandboxes.
+        state.ingest(vec![], vec![sandbox("p1", "uid-1")]);
+        state.observe(cri::ContainerEventResponse {
+            container_id: "c8".to_owned(),
+            pod_sandbox_status: Some(cri::PodSandboxStatus {
+                id: "p1".to_owned(),
+                metadata: sandbox("p1", "uid-1").metadata,
+                labels: sandbox("p1", "uid-1").labels,
+                ..Default::default()
+            }),
+            containers_statuses: vec![cri::ContainerStatus { id: "c8".to_owned(), state: 1, ..Default::default() }],
+            ..Default::default()
+        });
+        assert!(state.pods["uid-1"].ctrs.is_empty());
+        assert_eq!(state.unmanaged["p1"].ctrs["c8"], CS::ContainerRunning);
+    }
+
+    #[test]
+    fn ingest_reads_ownership_labels() {
+        let state = ingested(UnmanagedPolicy::Ignore);
+        assert_eq!(state.pods["uid-1"].generation, 1);
+    }
+
+    #[test]
+    fn other_agents_objects_are_never_touched() {
+        let mut theirs = sandbox("p4", "uid-4");
+        relabel(&mut theirs.labels, "agent-b");
+        let mut their_ctr = container("c6", "p4", "app", CS::ContainerRunning, 0);
+        relabel(&mut their_ctr.labels, "agent-b");
+        for policy in [UnmanagedPolicy::Ignore, UnmanagedPolicy::Adopt, UnmanagedPolicy::GarbageCollect] {
+            let mut state = new_state(policy);
+            state.ingest(vec![their_ctr.clone()], vec![theirs.clone()]);
+            assert!(state.pods.is_empty());
+            assert!(state.unmanaged.is_empty());
+            let plan = diff(&Target::new(), &state, SystemTime::now());
+            assert!(plan.pods.is_empty() && plan.unmanaged.is_empty());
+        }
+    }
+
+    #[test]
+    fn sandboxes_from_before_ownership_labels_are_adopted() {
+        // All that earlier agents labelled things with.
+        let old = |labels: &mut HashMap<String, String>| labels.retain(|key, _| key == NAME_LABEL);
+        let mut old_sandbox = sandbox("p1", "uid-1");
+        old(&mut old_sandbox.labels);
+        let mut old_ctr = container("c1", "p1", "app", CS::ContainerRunning, 0);
+        old(&mut old_ctr.labels);
+        let mut target = Target::new();
+        target.pods.insert("uid-1".to_owned(), pod_config("uid-1", &["app"]));
+
+        let mut state = new_state(UnmanagedPolicy::Ignore);
+        state.ingest(vec![old_ctr.clone()], vec![old_sandbox.clone()]);
+        assert!(state.pods.is_empty() && !state.has_unlabelled());
+
+        state.expect(&target);
+        state.ingest(vec![old_ctr], vec![old_sandbox.clone()]);
+        assert!(state.unmanaged.is_empty() && state.has_unlabelled());
+        assert_eq!(state.pods["uid-1"].ctrs["app"].id, "c1");
+        // Rather than creating another sandbox under the same name, which the runtime would refuse.
+        assert!(diff(&target, &state, SystemTime::now()).pods.is_empty());
+
+        // Only sandboxes for the pods in the target are taken for ours.
+        let mut other = old_sandbox;
+        other.metadata.as_mut().unwrap().uid = "uid-2".to_owned();
+        state.ingest(vec![], vec![other]);
+        assert!(state.pods.is_empty() && state.unmanaged.len() == 1);
+    }
+
+    fn with_hash<T>(mut obj: T, labels: impl FnOnce(&mut T) -> &mut HashMap<String, String>, hash: &str) -> T {
+        labels(&mut obj).insert(SPEC_HASH_LABEL.to_owned(), hash.to_owned());
+        obj
+    }
+
+    #[test]
+    fn spec_hash_ignores_generation() {
+        let config = pod_config("uid-1", &["app"]).config;
+        let bumped = SandBoxConfig { generation: 7, ..config.clone() };
+        assert_eq!(config.spec_hash(), bumped.spec_hash());
+        let renamed = SandBoxConfig { name: "api".to_owned(), ..config.clone() };
+        assert_ne!(config.spec_hash(), renamed.spec_hash());
+    }
+
+    #[test]
+    fn changed_containers_are_recreated() {
+        let target_pod = pod_config("uid-1", &["app", "sidecar"]);
+        let mut target = Target::new();
+        target.pods.insert("uid-1".to_owned(), target_pod.clone());
+        let mut old_app = target_pod.containers["app"].clone();
+        old_app.image = "docker.io/library/alpine:3.20".to_owned();
+
+        let mut state = new_state(UnmanagedPolicy::Ignore);
+        state.ingest(
+            vec![
+                with_hash(container("c1", "p1", "app", CS::ContainerRunning, 0), |c| &mut c.labels, &old_app.spec_hash()),
+                with_hash(container("c2", "p1", "sidecar", CS::ContainerRunning, 0), |c| &mut c.labels, &target_pod.containers["sidecar"].spec_hash()),
+            ],
+            vec![with_hash(sandbox("p1", "uid-1"), |p| &mut p.labels, &target_pod.config.spec_hash())],
+        );
+        let plan = diff(&target, &state, SystemTime::now());
+        match plan.pods.get("uid-1") {
+            Some(PodStep::ChangePod(steps)) => {
+                assert_eq!(steps.len(), 1);
+                assert!(matches!(steps.get("app"), Some(ContainerStep::StopCtr(id, _)) if id == "c1"));
+            }
+            _ => panic!("expected the changed container to be stopped"),
+        }
+
+        // Once it has exited, it's deleted, and then created from the new config.
+        state.ingest(
+            vec![with_hash(container("c1", "p1", "app", CS::ContainerExited, 0), |c| &mut c.labels, &old_app.spec_hash())],
+            vec![with_hash(sandbox("p1", "uid-1"), |p| &mut p.labels, &target_pod.config.spec_hash())],
+        );
+        assert!(matches!(diff(&target, &state, SystemTime::now()).pods.get("uid-1"),
+            Some(PodStep::ChangePod(steps)) if matches!(steps.get("app"), Some(ContainerStep::DeleteCtr(_)))));
+    }
+
+    #[test]
+    fn resources_are_updated_in_place() {
+        let mut target_pod = pod_config("uid-1", &["app"]);
+        let old_app = target_pod.containers["app"].clone();
+        let app = target_pod.containers.get_mut("app").unwrap();
+        app.resources = Resources { memory_mb: Some(256), ..Default::default() };
+        let wanted = app.resources.clone();
+        let mut target = Target::new();
+        target.pods.insert("uid-1".to_owned(), target_pod.clone());
+
+        let mut state = new_state(UnmanagedPolicy::Ignore);
+        state.ingest(
+            vec![with_hash(container("c1", "p1", "app", CS::ContainerRunning, 0), |c| &mut c.labels, &old_app.spec_hash())],
+            vec![with_hash(sandbox("p1", "uid-1"), |p| &mut p.labels, &target_pod.config.spec_hash())],
+        );
+        assert!(matches!(diff(&target, &state, SystemTime::now()).pods.get("uid-1"),
+            Some(PodStep::ChangePod(steps)) if matches!(steps.get("app"), Some(ContainerStep::UpdateResources(id, r)) if id == "c1" && *r == wanted)));
+
+        // Once it's been updated there's nothing left to do.
+        state.record_resize("c1".to_owned(), Some(wanted.clone()));
+        assert!(diff(&target, &state, SystemTime::now()).pods.is_empty());
+
+        // Dropping a limit can't be done in place, and neither can anything the runtime refused.
+        let mut target_pod = target_pod.clone();
+        target_pod.containers.get_mut("app").unwrap().resources = Default::default();
+        target.pods.insert("uid-1".to_owned(), target_pod.clone());
+        assert!(matches!(diff(&target, &state, SystemTime::now()).pods.get("uid-1"),
+            Some(PodStep::ChangePod(steps)) if matches!(steps.get("app"), Some(ContainerStep::StopCtr(id, _)) if id == "c1")));
+        target_pod.containers.get_mut("app").unwrap().resources = Resources { memory_mb: Some(512), ..Default::default() };
+        target.pods.insert("uid-1".to_owned(), target_pod);
+        state.record_resize("c1".to_owned(), None);
+        assert!(matches!(diff(&target, &state, SystemTime::now()).pods.get("uid-1"),
+            Some(PodStep::ChangePod(steps)) if matches!(steps.get("app"), Some(ContainerStep::StopCtr(id, _)) if id == "c1")));
+    }
+
+    #[test]
+    fn in_place_updates_stay_within_the_sandbox() {
+        let limited = |memory_mb| Resources { memory_mb: Some(memory_mb), ..Default::default() };
+        let mut target_pod = pod_config("uid-1", &["app", "log"]);
+        target_pod.containers.get_mut("app").unwrap().resources = limited(256);
+        target_pod.containers.get_mut("log").unwrap().resources = limited(64);
+        let running = |id: &str, name: &str| {
+            let config = &target_pod.containers[name];
+            let mut ctr = with_hash(container(id, "p1", name, CS::ContainerRunning, 0), |c| &mut c.labels, &config.spec_hash());
+            ctr.annotations.insert(RESOURCES_ANNOTATION.to_owned(), serde_json::to_string(&config.resources).unwrap());
+            ctr
+        };
+        let mut pod = with_hash(sandbox("p1", "uid-1"), |p| &mut p.labels, &target_pod.config.spec_hash());
+        pod.annotations.insert(RESOURCES_ANNOTATION.to_owned(), serde_json::to_string(&limited(320)).unwrap());
+        let mut state = new_state(UnmanagedPolicy::Ignore);
+        state.ingest(vec![running("c1", "app"), running("c2", "log")], vec![pod]);
+
+        let mut target = Target::new();
+        target_pod.containers.get_mut("app").unwrap().resources = limited(128);
+        target.pods.insert("uid-1".to_owned(), target_pod.clone());
+        assert!(matches!(diff(&target, &state, SystemTime::now()).pods.get("uid-1"),
+            Some(PodStep::ChangePod(steps)) if steps.len() == 1 && matches!(steps.get("app"), Some(ContainerStep::UpdateResources(..)))));
+
+        // More than the sandbox was given goes in place too.
+        target_pod.containers.get_mut("app").unwrap().resources = limited(512);
+        target.pods.insert("uid-1".to_owned(), target_pod);
+        assert!(matches!(diff(&target, &state, SystemTime::now()).pods.get("uid-1"),
+            Some(PodStep::ChangePod(steps)) if steps.len() == 1 && matches!(steps.get("app"), Some(ContainerStep::UpdateResources(..)))));
+        state.record_resize("c1".to_owned(), Some(limited(512)));
+        assert!(diff(&target, &state, SystemTime::now()).pods.is_empty());
+
+        // Only if the runtime won't is the whole pod recreated, to make room for the new container.
+        state.record_resize("c1".to_owned(), None);
+        state.resized.clear();
+        assert!(matches!(diff(&target, &state, SystemTime::now()).pods.get("uid-1"),
+            Some(PodStep::ChangePod(steps)) if steps.len() == 2 && steps.values().all(|step| matches!(step, ContainerStep::StopCtr(..)))));
+    }
 
-}
\ No newline at end of file
+    #[test]
+    fn changed_sandboxes_are_recreated() {
+        let mut target = Target::new();
+        target.pods.insert("uid-1".to_owned(), pod_config("uid-1", &["app"]));
+        let stale = "0000000000000000";
+
+        let mut state = new_state(UnmanagedPolicy::Ignore);
+        state.ingest(
+            vec![container("c1", "p1", "app", CS::ContainerRunning, 0)],
+            vec![with_hash(sandbox("p1", "uid-1"), |p| &mut p.labels, stale)],
+        );
+        assert!(matches!(diff(&target, &state, SystemTime::now()).pods.get("uid-1"),
+            Some(PodStep::ChangePod(steps)) if matches!(steps.get("app"), Some(ContainerStep::StopCtr(..)))));
+
+        state.ingest(
+            vec![container("c1", "p1", "app", CS::ContainerExited, 0)],
+            vec![with_hash(sandbox("p1", "uid-1"), |p| &mut p.labels, stale)],
+        );
+        assert!(matches!(diff(&target, &state, SystemTime::now()).pods.get("uid-1"), Some(PodStep::DeletePod(id)) if id == "p1"));
+
+        // Without a hash there's nothing to compare, so it's left alone.
+        state.ingest(vec![container("c1", "p1", "app", CS::ContainerRunning, 0)], vec![sandbox("p1", "uid-1")]);
+        assert!(diff(&target, &state, SystemTime::now()).pods.is_empty());
+    }
+
+    #[test]
+    fn outgrown_sandboxes_are_recreated() {
+        let limited = |memory_mb| Resources { memory_mb: Some(memory_mb), ..Default::default() };
+        let annotated = |mut obj: cri::PodSandbox, resources: &Resources| {
+            obj.annotations.insert(RESOURCES_ANNOTATION.to_owned(), serde_json::to_string(resources).unwrap());
+            obj
+        };
+        let mut target_pod = pod_config("uid-1", &["app"]);
+        target_pod.containers.get_mut("app").unwrap().resources = limited(64);
+        let mut target = Target::new();
+        target.pods.insert("uid-1".to_owned(), target_pod.clone());
+        let app = &target_pod.containers["app"];
+        let mut ctr = with_hash(container("c1", "p1", "app", CS::ContainerRunning, 0), |c| &mut c.labels, &app.spec_hash());
+        ctr.annotations.insert(RESOURCES_ANNOTATION.to_owned(), serde_json::to_string(&app.resources).unwrap());
+        let pod = with_hash(sandbox("p1", "uid-1"), |p| &mut p.labels, &target_pod.config.spec_hash());
+
+        let mut state = new_state(UnmanagedPolicy::Ignore);
+        state.ingest(vec![ctr.clone()], vec![annotated(pod.clone(), &limited(64))]);
+        assert!(diff(&target, &state, SystemTime::now()).pods.is_empty());
+
+        // Another container needs room of its own, which the sandbox was never given.
+        let mut log = ctr_config("log");
+        log.resources = limited(32);
+        target.pods.get_mut("uid-1").unwrap().containers.insert("log".to_owned(), log);
+        assert!(matches!(diff(&target, &state, SystemTime::now()).pods.get("uid-1"),
+            Some(PodStep::ChangePod(steps)) if matches!(steps.get("app"), Some(ContainerStep::StopCtr(..))) && !steps.contains_key("log")));
+
+        // A sandbox with room to spare is fine as it is.
+        state.ingest(vec![ctr.clone()], vec![annotated(pod.clone(), &limited(128))]);
+        assert!(matches!(diff(&target, &state, SystemTime::now()).pods.get("uid-1"),
+            Some(PodStep::ChangePod(steps)) if matches!(steps.get("log"), Some(ContainerStep::CreateCtr(..)))));
+
+        // Unless a container drops its limit, which the pod can't do without.
+        target.pods.get_mut("uid-1").unwrap().containers.get_mut("log").unwrap().resources = Resources::default();
+        assert!(matches!(diff(&target, &state, SystemTime::now()).pods.get("uid-1"),
+            Some(PodStep::ChangePod(steps)) if matches!(steps.get("app"), Some(ContainerStep::StopCtr(..))) && !steps.contains_key("log")));
+    }
+
+    #[test]
+    fn jobs_are_retried_up_to_their_backoff_limit() {
+        let mut target_pod = pod_config("uid-1", &["migrate"]);
+        let config = target_pod.containers.get_mut("migrate").unwrap();
+        config.run_mode = RunMode::Job;
+        config.backoff_limit = 1;
+        let mut target = Target::new();
+        target.pods.insert("uid-1".to_owned(), target_pod);
+        let mut state = new_state(UnmanagedPolicy::Ignore);
+        let step = |state: &State| match diff(&target, state, SystemTime::now()).pods.remove("uid-1") {
+            Some(PodStep::ChangePod(mut steps)) => steps.remove("migrate"),
+            _ => None,
+        };
+
+        // Until we know how it exited, it's left alone.
+        state.ingest(vec![container("c1", "p1", "migrate", CS::ContainerExited, 0)], vec![sandbox("p1", "uid-1")]);
+        assert!(step(&state).is_none());
+        state.set_exit(&exited("c1", 1));
+        assert!(matches!(step(&state), Some(ContainerStep::DeleteCtr(_))));
+
+        // The history outlives the container.
+        state.ingest(vec![], vec![sandbox("p1", "uid-1")]);
+        assert!(matches!(step(&state), Some(ContainerStep::CreateCtr(..))));
+        state.ingest(vec![container("c2", "p1", "migrate", CS::ContainerExited, 0)], vec![sandbox("p1", "uid-1")]);
+        state.set_exit(&exited("c2", 1));
+        assert_eq!(state.history["uid-1"]["migrate"].failures, 2);
+        assert!(step(&state).is_none());
+        assert_eq!(state.job_outcome(&"uid-1".to_owned(), &"migrate".to_owned(), &target.pods["uid-1"].containers["migrate"]),
+            Some(JobOutcome::Failed));
+    }
+
+    #[test]
+    fn succeeded_jobs_are_left_alone() {
+        let mut target_pod = pod_config("uid-1", &["migrate"]);
+        target_pod.containers.get_mut("migrate").unwrap().run_mode = RunMode::Job;
+        let mut target = Target::new();
+        target.pods.insert("uid-1".to_owned(), target_pod);
+
+        let mut state = new_state(UnmanagedPolicy::Ignore);
+        state.ingest(vec![container("c1", "p1", "migrate", CS::ContainerExited, 0)], vec![sandbox("p1", "uid-1")]);
+        state.set_exit(&exited("c1", 0));
+        assert!(diff(&target, &state, SystemTime::now()).pods.is_empty());
+        // Even once the container has been cleaned up.
+        state.ingest(vec![], vec![sandbox("p1", "uid-1")]);
+        assert!(diff(&target, &state, SystemTime::now()).pods.is_empty());
+    }
+
+    fn exited_at(id: &str, exit_code: i32, started_secs: u64, finished_secs: u64) -> cri::ContainerStatus {
+        cri::ContainerStatus {
+            started_at: (started_secs * 1_000_000_000) as i64,
+            finished_at: (finished_secs * 1_000_000_000) as i64,
+            ..exited(id, exit_code)
+        }
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn crash_loop_backoff_doubles_up_to_a_cap() {
+        let backoffs: Vec<u64> = (0..8)
+            .map(|crashes| CtrHistory { crashes, ..Default::default() }.backoff().as_secs())
+            .collect();
+        assert_eq!(backoffs, vec![0, 10, 20, 40, 80, 160, 300, 300]);
+    }
+
+    #[test]
+    fn crash_looping_containers_are_held_back() {
+        let mut target = Target::new();
+        target.pods.insert("uid-1".to_owned(), pod_config("uid-1", &["app"]));
+        let step = |state: &State, now| {
+            let mut plan = diff(&target, state, now);
+            let step = match plan.pods.remove("uid-1") {
+                Some(PodStep::ChangePod(mut steps)) => steps.remove("app"),
+                _ => None,
+            };
+            (step, plan.wake_at)
+        };
+        let mut state = new_state(UnmanagedPolicy::Ignore);
+        state.ingest(vec![container("c1", "p1", "app", CS::ContainerExited, 0)], vec![sandbox("p1", "uid-1")]);
+        state.set_exit(&exited_at("c1", 1, 1000, 1001));
+        assert!(matches!(step(&state, at(1005)), (None, Some(wake_at)) if wake_at == at(1011)));
+        assert!(matches!(step(&state, at(1011)), (Some(ContainerStep::DeleteCtr(_)), None)));
+
+        // The next one waits twice as long, whether or not its predecessor is still around.
+        state.ingest(vec![container("c2", "p1", "app", CS::ContainerExited, 0)], vec![sandbox("p1", "uid-1")]);
+        state.set_exit(&exited_at("c2", 1, 1012, 1013));
+        state.ingest(vec![], vec![sandbox("p1", "uid-1")]);
+        assert!(matches!(step(&state, at(1030)), (None, Some(wake_at)) if wake_at == at(1033)));
+        assert!(matches!(step(&state, at(1033)), (Some(ContainerStep::CreateCtr(..)), None)));
+
+        // Running for long enough starts the backoff over.
+        state.ingest(vec![container("c3", "p1", "app", CS::ContainerExited, 0)], vec![sandbox("p1", "uid-1")]);
+        state.set_exit(&exited_at("c3", 1, 1033, 2000));
+        assert_eq!(state.history["uid-1"]["app"].crashes, 1);
+        assert!(matches!(step(&state, at(2005)), (None, Some(wake_at)) if wake_at == at(2010)));
+    }
+
+    #[test]
+    fn restart_policies_decide_what_gets_restarted() {
+        let mut state = new_state(UnmanagedPolicy::Ignore);
+        state.ingest(
+            vec![
+                container("c1", "p1", "ok", CS::ContainerExited, 0),
+                container("c2", "p1", "failed", CS::ContainerExited, 0),
+            ],
+            vec![sandbox("p1", "uid-1")],
+        );
+        state.set_exit(&exited("c1", 0));
+        state.set_exit(&exited("c2", 2));
+
+        let steps = |policy| {
+            let mut target = Target::new();
+            let pod = PodConfig { restart_policy: policy, ..pod_config("uid-1", &["ok", "failed", "missing"]) };
+            target.pods.insert("uid-1".to_owned(), pod);
+            match diff(&target, &state, SystemTime::now()).pods.remove("uid-1") {
+                Some(PodStep::ChangePod(steps)) => {
+                    let mut names: Vec<Name> = steps.into_keys().collect();
+                    names.sort();
+                    names
+                }
+                _ => vec![],
+            }
+        };
+        assert_eq!(steps(RestartPolicy::Always), vec!["failed", "missing", "ok"]);
+        assert_eq!(steps(RestartPolicy::OnFailure), vec!["failed", "missing"]);
+        // Containers that never ran are still started.
+        assert_eq!(steps(RestartPolicy::Never), vec!["missing"]);
+    }
+
+    #[test]
+    fn env_values_come_from_the_target() {
+        let mut target = Target::new();
+        let mut podconfig = pod_config("uid-1", &["app"]);
+        let secret = |key: &str| EnvSource::Secret { secret: "db".to_owned(), key: key.to_owned() };
+        podconfig.containers.get_mut("app").unwrap().envs = vec![
+            EnvVar::new("PLAIN", "1"),
+            EnvVar { value_from: Some(secret("password")), ..EnvVar::new("PASSWORD", "") },
+            EnvVar { value_from: Some(secret("missing")), ..EnvVar::new("MISSING", "") },
+        ];
+        target.pods.insert("uid-1".to_owned(), podconfig);
+        target.secrets.insert("db".to_owned(), Files::from([("password".to_owned(), "hunter2".to_owned())]));
+        let mut state = new_state(UnmanagedPolicy::Ignore);
+        state.ingest(vec![], vec![sandbox("p1", "uid-1")]);
+        match diff(&target, &state, SystemTime::now()).pods.remove("uid-1") {
+            Some(PodStep::ChangePod(steps)) => match steps.get("app") {
+                Some(ContainerStep::CreateCtr(.., values)) => {
+                    assert_eq!(values.0, HashMap::from([("PASSWORD".to_owned(), "hunter2".to_owned())]));
+                    assert_eq!(format!("{:?}", values), "{\"PASSWORD\"}");
+                }
+                step => panic!("expected the container to be created, got {:?}", step),
+            },
+            step => panic!("expected the pod to be changed, got {:?}", step),
+        }
+    }
+
+    #[test]
+    fn replacements_get_the_next_attempt() {
+        let mut target = Target::new();
+        target.pods.insert("uid-1".to_owned(), pod_config("uid-1", &["app"]));
+        let mut state = new_state(UnmanagedPolicy::Ignore);
+        let mut old_sandbox = sandbox("p1", "uid-1");
+        old_sandbox.metadata.as_mut().unwrap().attempt = 2;
+        state.ingest(vec![container("c1", "p1", "app", CS::ContainerRunning, 4)], vec![old_sandbox]);
+        assert_eq!(state.pods["uid-1"].attempt, 2);
+
+        // The container goes away...
+        state.ingest(vec![], vec![sandbox("p1", "uid-1")]);
+        match diff(&target, &state, SystemTime::now()).pods.remove("uid-1") {
+            Some(PodStep::ChangePod(steps)) => match steps.get("app") {
+                Some(ContainerStep::CreateCtr(_, config, ..)) => assert_eq!(config.attempt, 5),
+                step => panic!("expected the container to be created, got {:?}", step),
+            },
+            step => panic!("expected the pod to be changed, got {:?}", step),
+        }
+        // ...and then the whole pod.
+        state.ingest(vec![], vec![]);
+        state.prune(&target);
+        assert!(matches!(diff(&target, &state, SystemTime::now()).pods.remove("uid-1"),
+            Some(PodStep::CreatePod(config)) if config.attempt == 3));
+
+        // Once it's gone from the target too, there's nothing left to remember.
+        state.prune(&Target::new());
+        assert!(state.attempts.is_empty());
+    }
+
+    #[test]
+    fn containers_are_stopped_as_they_asked() {
+        let mut labelled = container("c1", "p1", "app", CS::ContainerRunning, 0);
+        labelled.labels.insert(GRACE_PERIOD_LABEL.to_owned(), "120".to_owned());
+        labelled.labels.insert(STOP_SIGNAL_LABEL.to_owned(), "SIGQUIT".to_owned());
+        let mut state = new_state(UnmanagedPolicy::Ignore);
+        state.ingest(
+            vec![labelled, container("c2", "p1", "sidecar", CS::ContainerRunning, 0)],
+            vec![sandbox("p1", "uid-1")],
+        );
+        match diff(&Target::new(), &state, SystemTime::now()).pods.remove("uid-1") {
+            Some(PodStep::ChangePod(steps)) => {
+                let expected = StopOptions { grace_period: Duration::from_secs(120), signal: Some("SIGQUIT".to_owned()), pre_stop: None };
+                assert!(matches!(steps.get("app"), Some(ContainerStep::StopCtr(_, stop)) if *stop == expected));
+                let default = StopOptions { grace_period: Duration::from_secs(30), signal: None, pre_stop: None };
+                assert!(matches!(steps.get("sidecar"), Some(ContainerStep::StopCtr(_, stop)) if *stop == default));
+            }
+            step => panic!("expected the containers to be stopped, got {:?}", step),
+        }
+    }
+
+    #[test]
+    fn hooks_go_along_with_their_steps() {
+        let hook = Hook::Exec { command: vec!["/bin/drain".to_owned()] };
+        let mut running = container("c1", "p1", "app", CS::ContainerRunning, 0);
+        running.annotations.insert(PRE_STOP_ANNOTATION.to_owned(), serde_json::to_string(&hook).unwrap());
+        let mut state = new_state(UnmanagedPolicy::Ignore);
+        state.ingest(
+            vec![running, container("c2", "p1", "sidecar", CS::ContainerCreated, 0)],
+            vec![sandbox("p1", "uid-1")],
+        );
+        let mut pod = pod_config("uid-1", &["sidecar"]);
+        pod.containers.get_mut("sidecar").unwrap().lifecycle.post_start = Some(hook.clone());
+        let mut target = Target::new();
+        target.pods.insert("uid-1".to_owned(), pod);
+        match diff(&target, &state, SystemTime::now()).pods.remove("uid-1") {
+            Some(PodStep::ChangePod(steps)) => {
+                assert!(matches!(steps.get("app"), Some(ContainerStep::StopCtr(_, stop)) if stop.pre_stop == Some(hook.clone())));
+                assert!(matches!(steps.get("sidecar"), Some(ContainerStep::StartCtr(_, post_start)) if *post_start == Some(hook.clone())));
+            }
+            step => panic!("expected the containers to be changed, got {:?}", step),
+        }
+    }
+
+    #[test]
+    fn unhealthy_containers_are_stopped() {
+        let mut state = new_state(UnmanagedPolicy::Ignore);
+        state.ingest(vec![container("c1", "p1", "app", CS::ContainerRunning, 0)], vec![sandbox("p1", "uid-1")]);
+        let mut target = Target::new();
+        target.pods.insert("uid-1".to_owned(), pod_config("uid-1", &["app"]));
+        assert!(diff(&target, &state, SystemTime::now()).pods.is_empty());
+
+        state.record_probe(ProbeResult { id: "c1".to_owned(), kind: ProbeKind::Liveness, passing: false });
+        state.prune(&target);
+        assert!(matches!(diff(&target, &state, SystemTime::now()).pods.get("uid-1"),
+            Some(PodStep::ChangePod(steps)) if matches!(steps.get("app"), Some(ContainerStep::StopCtr(id, _)) if id == "c1")));
+
+        // Once it has stopped, it's restarted like any other crash, and its probe's verdict is forgotten.
+        state.ingest(vec![container("c1", "p1", "app", CS::ContainerExited, 0)], vec![sandbox("p1", "uid-1")]);
+        state.prune(&target);
+        assert!(state.unhealthy.is_empty());
+    }
+
+    #[test]
+    fn startup_probes_hold_back_readiness() {
+        let probe = Probe { check: Check::TcpSocket { port: 80 }, initial_delay: 0, period: 10, timeout: 1, failure_threshold: 3 };
+        let mut pod = pod_config("uid-1", &["app", "migrate"]);
+        pod.containers.get_mut("app").unwrap().startup_probe = Some(probe);
+        pod.containers.get_mut("migrate").unwrap().run_mode = RunMode::Job;
+        let mut target = Target::new();
+        target.pods.insert("uid-1".to_owned(), pod);
+        let mut state = new_state(UnmanagedPolicy::Ignore);
+        state.ingest(vec![container("c1", "p1", "app", CS::ContainerRunning, 0)], vec![sandbox("p1", "uid-1")]);
+        state.update_readiness(&target, &HashSet::new());
+        assert!(!state.pods["uid-1"].ctrs["app"].ready);
+
+        state.record_probe(ProbeResult { id: "c1".to_owned(), kind: ProbeKind::Startup, passing: true });
+        state.update_readiness(&target, &HashSet::new());
+        // The Job hasn't even been created, but only service containers count.
+        assert!(state.pods["uid-1"].ctrs["app"].ready && state.pods["uid-1"].ready);
+
+        state.record_probe(ProbeResult { id: "c1".to_owned(), kind: ProbeKind::Startup, passing: false });
+        assert!(state.unhealthy.contains("c1"));
+    }
+
+    #[test]
+    fn init_containers_run_in_order_first() {
+        let mut pod = pod_config("uid-1", &["app"]);
+        pod.init_containers = vec![ctr_config("setup-a"), ctr_config("setup-b")];
+        let mut target = Target::new();
+        target.pods.insert("uid-1".to_owned(), pod);
+        let created = |plan: Plan| -> Vec<Name> {
+            match plan.pods.get("uid-1") {
+                Some(PodStep::ChangePod(steps)) => {
+                    let mut names: Vec<Name> = steps.iter()
+                        .filter(|(_, step)| matches!(step, ContainerStep::CreateCtr(..)))
+                        .map(|(name, _)| name.clone())
+                        .collect();
+                    names.sort();
+                    names
+                }
+                step => panic!("expected containers to be created, got {:?}", step),
+            }
+        };
+
+        let mut state = new_state(UnmanagedPolicy::Ignore);
+        state.ingest(vec![], vec![sandbox("p1", "uid-1")]);
+        assert_eq!(created(diff(&target, &state, SystemTime::now())), vec!["setup-a"]);
+
+        let mut ctrs = vec![container("c1", "p1", "setup-a", CS::ContainerExited, 0)];
+        state.ingest(ctrs.clone(), vec![sandbox("p1", "uid-1")]);
+        state.set_exit(&exited("c1", 0));
+        assert_eq!(created(diff(&target, &state, SystemTime::now())), vec!["setup-b"]);
+
+        // A failed init container holds everything up until it's been retried.
+        ctrs.push(container("c2", "p1", "setup-b", CS::ContainerExited, 0));
+        state.ingest(ctrs.clone(), vec![sandbox("p1", "uid-1")]);
+        state.set_exit(&exited("c2", 1));
+        assert!(matches!(diff(&target, &state, SystemTime::now()).pods.get("uid-1"),
+            Some(PodStep::ChangePod(steps)) if steps.len() == 1 && matches!(steps.get("setup-b"), Some(ContainerStep::DeleteCtr(_)))));
+
+        ctrs[1] = container("c3", "p1", "setup-b", CS::ContainerExited, 1);
+        state.ingest(ctrs.clone(), vec![sandbox("p1", "uid-1")]);
+        state.set_exit(&exited("c3", 0));
+        assert_eq!(created(diff(&target, &state, SystemTime::now())), vec!["app"]);
+
+        // A new sandbox starts over.
+        state.ingest(vec![], vec![sandbox("p2", "uid-1")]);
+        assert_eq!(created(diff(&target, &state, SystemTime::now())), vec!["setup-a"]);
+    }
+
+    #[test]
+    fn dependencies_start_first_and_stop_last() {
+        let mut pod = pod_config("uid-1", &["app", "proxy"]);
+        pod.containers.get_mut("app").unwrap().depends_on = vec!["proxy".to_owned()];
+        let mut target = Target::new();
+        target.pods.insert("uid-1".to_owned(), pod);
+        let mut app = container("c1", "p1", "app", CS::ContainerCreated, 0);
+        app.labels.insert(DEPENDS_ON_LABEL.to_owned(), "proxy".to_owned());
+        let mut state = new_state(UnmanagedPolicy::Ignore);
+        state.ingest(
+            vec![app.clone(), container("c2", "p1", "proxy", CS::ContainerCreated, 0)],
+            vec![sandbox("p1", "uid-1")],
+        );
+        state.update_readiness(&target, &HashSet::new());
+        match diff(&target, &state, SystemTime::now()).pods.remove("uid-1") {
+            Some(PodStep::ChangePod(steps)) => {
+                assert!(matches!(steps.get("proxy"), Some(ContainerStep::StartCtr(..))));
+                assert!(steps.get("app").is_none());
+            }
+            step => panic!("expected the proxy to be started, got {:?}", step),
+        }
+
+        state.ingest(
+            vec![app.clone(), container("c2", "p1", "proxy", CS::ContainerRunning, 0)],
+            vec![sandbox("p1", "uid-1")],
+        );
+        // Not while the proxy's PostStart hook is still running.
+        state.update_readiness(&target, &HashSet::from(["c2".to_owned()]));
+        assert!(!state.pods["uid-1"].ctrs["proxy"].ready);
+        assert!(diff(&target, &state, SystemTime::now()).pods.is_empty());
+        state.update_readiness(&target, &HashSet::new());
+        assert!(matches!(diff(&target, &state, SystemTime::now()).pods.get("uid-1"),
+            Some(PodStep::ChangePod(steps)) if matches!(steps.get("app"), Some(ContainerStep::StartCtr(..)))));
+
+        app.state = CS::ContainerRunning.into();
+        state.ingest(
+            vec![app, container("c2", "p1", "proxy", CS::ContainerRunning, 0)],
+            vec![sandbox("p1", "uid-1")],
+        );
+        match diff(&Target::new(), &state, SystemTime::now()).pods.remove("uid-1") {
+            Some(PodStep::ChangePod(steps)) => {
+                assert!(matches!(steps.get("app"), Some(ContainerStep::StopCtr(..))));
+                assert!(steps.get("proxy").is_none());
+            }
+            step => panic!("expected the app to be stopped first, got {:?}", step),
+        }
+    }
+
+    #[test]
+    fn dependents_stop_before_what_they_depend_on_is_replaced() {
+        let mut target_pod = pod_config("uid-1", &["app", "proxy"]);
+        target_pod.containers.get_mut("app").unwrap().depends_on = vec!["proxy".to_owned()];
+        let mut target = Target::new();
+        target.pods.insert("uid-1".to_owned(), target_pod.clone());
+        let mut old_proxy = target_pod.containers["proxy"].clone();
+        old_proxy.image = "docker.io/envoyproxy/envoy:v1.30".to_owned();
+        let mut app = container("c1", "p1", "app", CS::ContainerRunning, 0);
+        app.labels.insert(DEPENDS_ON_LABEL.to_owned(), "proxy".to_owned());
+        let proxy = with_hash(container("c2", "p1", "proxy", CS::ContainerRunning, 0), |c| &mut c.labels, &old_proxy.spec_hash());
+        let mut state = new_state(UnmanagedPolicy::Ignore);
+        state.ingest(vec![app.clone(), proxy.clone()], vec![sandbox("p1", "uid-1")]);
+        match diff(&target, &state, SystemTime::now()).pods.remove("uid-1") {
+            Some(PodStep::ChangePod(steps)) => {
+                assert!(matches!(steps.get("app"), Some(ContainerStep::StopCtr(id, _)) if id == "c1"));
+                assert!(steps.get("proxy").is_none());
+            }
+            step => panic!("expected the app to be stopped first, got {:?}", step),
+        }
+
+        // The same goes for a proxy that's dropped from the pod.
+        let mut without_proxy = target.clone();
+        without_proxy.pods.get_mut("uid-1").unwrap().containers.remove("proxy");
+        without_proxy.pods.get_mut("uid-1").unwrap().containers.get_mut("app").unwrap().depends_on.clear();
+        match diff(&without_proxy, &state, SystemTime::now()).pods.remove("uid-1") {
+            Some(PodStep::ChangePod(steps)) => {
+                assert!(matches!(steps.get("app"), Some(ContainerStep::StopCtr(id, _)) if id == "c1"));
+                assert!(steps.get("proxy").is_none());
+            }
+            step => panic!("expected the app to be stopped first, got {:?}", step),
+        }
+
+        app.state = CS::ContainerExited.into();
+        state.ingest(vec![app, proxy], vec![sandbox("p1", "uid-1")]);
+        state.set_exit(&exited("c1", 143));
+        match diff(&target, &state, SystemTime::now()).pods.remove("uid-1") {
+            Some(PodStep::ChangePod(steps)) => {
+                assert!(matches!(steps.get("proxy"), Some(ContainerStep::StopCtr(id, _)) if id == "c2"));
+                assert!(matches!(steps.get("app"), Some(ContainerStep::DeleteCtr(id)) if id == "c1"));
+            }
+            step => panic!("expected the proxy to be stopped once the app has, got {:?}", step),
+        }
+    }
+
+    #[test]
+    fn sidecars_stop_once_the_job_is_done() {
+        let mut pod = pod_config("uid-1", &["migrate", "logs"]);
+        pod.containers.get_mut("migrate").unwrap().run_mode = RunMode::Job;
+        pod.containers.get_mut("logs").unwrap().sidecar = true;
+        let mut target = Target::new();
+        target.pods.insert("uid-1".to_owned(), pod);
+        let ctrs = vec![
+            container("c1", "p1", "migrate", CS::ContainerExited, 0),
+            container("c2", "p1", "logs", CS::ContainerRunning, 0),
+        ];
+        let mut state = new_state(UnmanagedPolicy::Ignore);
+        state.ingest(ctrs.clone(), vec![sandbox("p1", "uid-1")]);
+        state.set_exit(&exited("c1", 1));
+        // Still being retried.
+        assert!(matches!(diff(&target, &state, SystemTime::now()).pods.get("uid-1"),
+            Some(PodStep::ChangePod(steps)) if steps.get("logs").is_none()));
+
+        let mut ctrs = ctrs;
+        ctrs[0] = container("c3", "p1", "migrate", CS::ContainerExited, 1);
+        state.ingest(ctrs.clone(), vec![sandbox("p1", "uid-1")]);
+        state.set_exit(&exited("c3", 0));
+        assert!(matches!(diff(&target, &state, SystemTime::now()).pods.get("uid-1"),
+            Some(PodStep::ChangePod(steps)) if matches!(steps.get("logs"), Some(ContainerStep::StopCtr(id, _)) if id == "c2")));
+
+        // And once it has stopped, it stays stopped.
+        ctrs[1] = container("c2", "p1", "logs", CS::ContainerExited, 0);
+        state.ingest(ctrs, vec![sandbox("p1", "uid-1")]);
+        state.set_exit(&exited("c2", 143));
+        assert!(diff(&target, &state, SystemTime::now()).pods.get("uid-1").is_none());
+    }
+}
@@ -0,0 +1,220 @@
+use std::path::{Path, PathBuf};
+use futures_util::{FutureExt, StreamExt};
+use inotify::{Inotify, WatchMask};
+use tokio::sync::watch::Sender as WatchTx;
+use crate::common::*;
+use crate::manifest::{self, Format};
+use crate::state::Target;
+
+pub const DEFAULT_MANIFEST_DIR: &'static str = "/etc/hyphae/pods";
+
+// Editors and config management tend to write files in bursts. Wait this long for things to
+// settle before re-reading the directory.
+const MANIFEST_SETTLE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// A directory of static pod manifests, one pod per file.
+/// Files ending in .toml, .yaml, .yml or .json are read; everything else is ignored.
+pub struct ManifestDir {
+    dir: PathBuf,
+    // If a manifest is mid-edit or broken, keep running what it last described
+    // instead of tearing the pod down.
+    last_good: HashMap<PathBuf, PodConfig>,
+}
+
+impl ManifestDir {
+    pub fn new(dir: PathBuf) -> ManifestDir {
+        ManifestDir { dir, last_good: HashMap::new() }
+    }
+
+    /// Read every manifest in the directory and build a Target from them.
+    pub fn load(&mut self) -> Target {
+        let mut target = Target::new();
+        let mut paths = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .collect::<Vec<_>>(),
+            Err(e) => {
+                log_err(Error::from(e));
+                return target;
+            }
+        };
+        paths.sort();
+
+        let mut last_good = HashMap::new();
+        for path in paths {
+            let pod = match parse_manifest(&path) {
+                Ok(Some(pod)) => pod,
+                Ok(None) => { continue; }
+                Err(e) => {
+                    log_err(e);
+                    match self.last_good.remove(&path) {
+                        Some(pod) => pod,
+                        None => { continue; }
+                    }
+                }
+            };
+            let uid = pod.config.uid.clone();
+            if target.pods.contains_key(&uid) {
+                log_err(Error::ManifestError(
+                    format!("{}: pod uid {} is already used by another manifest", path.display(), uid)
+                ));
+                continue;
+            }
+            last_good.insert(path, pod.clone());
+            target.pods.insert(uid, pod);
+        }
+        self.last_good = last_good;
+        target
+    }
+}
+
+fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map_or(true, |name| name.starts_with('.'))
+}
+
+/// Parse a single manifest file. Returns None if the file is not a manifest.
+fn parse_manifest(path: &Path) -> Result<Option<PodConfig>, Error> {
+    if is_hidden(path) || !path.is_file() {
+        return Ok(None);
+    }
+    let format = match Format::from_path(path) {
+        Some(format) => format,
+        None => { return Ok(None); }
+    };
+    let contents = std::fs::read_to_string(path)?;
+    manifest::parse_pod(&contents, format)
+        .map(Some)
+        .map_err(|e| Error::ManifestError(format!("{}: {}", path.display(), e)))
+}
+
+/// Build the target from a directory of manifests and re-publish it whenever the directory changes.
+pub async fn watch_manifests(dir: PathBuf, target_tx: WatchTx<Target>) -> Result<(), Error> {
+    std::fs::create_dir_all(&dir)?;
+    let inotify = Inotify::init()?;
+    // Watch before the first read so that nothing written in between is missed.
+    inotify.watches().add(
+        &dir,
+        WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::MOVED_FROM | WatchMask::DELETE,
+    )?;
+    let mut events = inotify.into_event_stream([0u8; 4096])?;
+
+    let mut manifests = ManifestDir::new(dir);
+    crate::publish_target(&target_tx, manifests.load());
+    while let Some(event) = events.next().await {
+        // Re-reading the directory is the answer to any event, so a bad one is no reason to stop.
+        if let Err(e) = event {
+            log_err(Error::from(e));
+        }
+        tokio::time::sleep(MANIFEST_SETTLE_INTERVAL).await;
+        while let Some(Some(_)) = events.next().now_or_never() {}
+        crate::publish_target(&target_tx, manifests.load());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NGINX_TOML: &'static str = r#"
+api_version = "hyphae/v1"
+
+[sandbox]
+name = "web"
+uid = "uid-web"
+
+[containers.nginx]
+image = "docker.io/library/nginx:latest"
+command = "nginx"
+args = ["-g", "daemon off;"]
+"#;
+
+    const EXPORTER_YAML: &'static str = r#"
+api_version: hyphae/v1
+sandbox:
+  name: exporter
+  uid: uid-exporter
+  namespace: kube-system
+containers:
+  node-exporter:
+    image: quay.io/prometheus/node-exporter:latest
+    privileged: true
+"#;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("hyphae-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn load_reads_one_pod_per_file() {
+        let dir = scratch_dir("load");
+        std::fs::write(dir.join("web.toml"), NGINX_TOML).unwrap();
+        std::fs::write(dir.join("exporter.yaml"), EXPORTER_YAML).unwrap();
+        std::fs::write(dir.join("README"), "not a manifest").unwrap();
+        std::fs::write(dir.join(".web.toml.swp"), "garbage").unwrap();
+
+        let target = ManifestDir::new(dir.clone()).load();
+        assert_eq!(target.pods.len(), 2);
+        assert_eq!(target.pods["uid-web"].containers["nginx"].image, "docker.io/library/nginx:latest");
+        assert!(target.pods["uid-exporter"].containers["node-exporter"].privileged);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn load_keeps_last_good_manifest() {
+        let dir = scratch_dir("last-good");
+        let mut manifests = ManifestDir::new(dir.clone());
+        std::fs::write(dir.join("web.toml"), NGINX_TOML).unwrap();
+        let first = manifests.load();
+
+        std::fs::write(dir.join("web.toml"), "[sandbox\nname = ").unwrap();
+        assert_eq!(manifests.load(), first);
+
+        std::fs::remove_file(dir.join("web.toml")).unwrap();
+        assert!(manifests.load().pods.is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn load_rejects_duplicate_uids() {
+        let dir = scratch_dir("duplicates");
+        std::fs::write(dir.join("a.toml"), NGINX_TOML).unwrap();
+        std::fs::write(dir.join("b.toml"), NGINX_TOML).unwrap();
+        let target = ManifestDir::new(dir.clone()).load();
+        assert_eq!(target.pods.len(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn watch_publishes_new_manifests() {
+        let dir = scratch_dir("watch");
+        let (target_tx, mut target_rx) = tokio::sync::watch::channel(Target::new());
+        let watcher = tokio::spawn(watch_manifests(dir.clone(), target_tx));
+
+        // Give the watcher a moment to register before writing.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        std::fs::write(dir.join("web.toml"), NGINX_TOML).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                target_rx.changed().await.unwrap();
+                if target_rx.borrow_and_update().pods.contains_key("uid-web") { break; }
+            }
+        }).await.unwrap();
+
+        std::fs::remove_file(dir.join("web.toml")).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                target_rx.changed().await.unwrap();
+                if target_rx.borrow_and_update().pods.is_empty() { break; }
+            }
+        }).await.unwrap();
+        watcher.abort();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,309 @@
+use serde::Serialize;
+use tokio::sync::watch::Receiver as WatchRx;
+use crate::common::*;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::state::{JobOutcome, Restart, State, Target};
+use crate::worktree::WorkTree;
+
+const STATUS_REPORT_INTERVAL: Duration = Duration::from_millis(30_000);
+const STATUS_REPORT_TIMEOUT: Duration = Duration::from_millis(10_000);
+// After a significant change, wait a moment for related changes (e.g. the rest of a pod starting)
+// so that they go out in one report.
+const STATUS_SETTLE_INTERVAL: Duration = Duration::from_millis(1_000);
+
+/// What the node is actually running, as reported to the control plane.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct NodeStatus {
+    pub node: String,
+    pub pods: Vec<PodReport>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PodReport {
+    pub uid: UID,
+    pub sandbox_id: PodId,
+    pub generation: u64,
+    /// How many times the pod's sandbox has been recreated.
+    pub restart_count: u32,
+    /// Whether the pod is ready for traffic, i.e. all of its service containers are.
+    pub ready: bool,
+    pub containers: Vec<CtrReport>,
+    /// What we're doing to the sandbox, if anything, e.g. "running, attempt 3".
+    pub task: Option<String>,
+    pub last_error: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CtrReport {
+    pub name: Name,
+    pub id: CtrId,
+    /// The container's state, or for a finished Job, "succeeded" or "failed".
+    pub state: &'static str,
+    pub exit_code: Option<i32>,
+    pub ready: bool,
+    pub restart_count: u32,
+    /// If it's in a crash loop, when it will next be restarted, in seconds since the epoch.
+    pub backoff_until: Option<u64>,
+    pub task: Option<String>,
+    pub last_error: Option<String>,
+    /// Why its last PostStart or PreStop hook failed, if one did.
+    pub hook_error: Option<String>,
+}
+
+fn state_name(state: cri::ContainerState) -> &'static str {
+    use cri::ContainerState as CS;
+    match state {
+        CS::ContainerCreated => "created",
+        CS::ContainerRunning => "running",
+        CS::ContainerExited => "exited",
+        CS::ContainerUnknown => "unknown",
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+impl NodeStatus {
+    pub fn new(node: String) -> NodeStatus {
+        NodeStatus { node, pods: vec![] }
+    }
+
+    /// Take a snapshot of the node's state, along with any errors the worktree has run into.
+    pub fn snapshot(node: String, state: &State, target: &Target, worktree: &WorkTree) -> NodeStatus {
+        let mut pods: Vec<PodReport> = state.pods.iter()
+            .map(|(uid, pod)| {
+                let mut containers: Vec<CtrReport> = pod.ctrs.iter()
+                    .map(|(name, ctr)| (name, ctr, worktree.ctr_status(uid, name)))
+                    .map(|(name, ctr, task)| CtrReport {
+                        name: name.clone(),
+                        id: ctr.id.clone(),
+                        state: match target.pods.get(uid).and_then(|p| p.containers.get(name)) {
+                            Some(config) => match state.job_outcome(uid, name, config) {
+                                Some(JobOutcome::Succeeded) => "succeeded",
+                                Some(JobOutcome::Failed) => "failed",
+                                None => state_name(ctr.state),
+                            },
+                            None => state_name(ctr.state),
+                        },
+                        exit_code: ctr.exit_code,
+                        ready: ctr.ready,
+                        restart_count: ctr.attempt,
+                        backoff_until: match target.pods.get(uid) {
+                            Some(config) if ctr.state == cri::ContainerState::ContainerExited => {
+                                match state.next_start(uid, name, config) {
+                                    Restart::At(time) => Some(unix_secs(time)),
+                                    _ => None,
+                                }
+                            }
+                            _ => None,
+                        },
+                        last_error: task.as_ref().and_then(|task| task.last_error()),
+                        task: task.map(|task| task.to_string()),
+                        hook_error: worktree.hook_error(&ctr.id),
+                    })
+                    .collect();
+                containers.sort_by(|a, b| a.name.cmp(&b.name));
+                let task = worktree.pod_status(uid);
+                PodReport {
+                    uid: uid.clone(),
+                    sandbox_id: pod.id.clone(),
+                    generation: pod.generation,
+                    restart_count: pod.attempt,
+                    ready: pod.ready,
+                    containers,
+                    last_error: task.as_ref().and_then(|task| task.last_error()),
+                    task: task.map(|task| task.to_string()),
+                }
+            })
+            .collect();
+        // Pods we haven't managed to create yet, so there's only the error to report.
+        for (uid, podconfig) in target.pods.iter() {
+            if state.pods.contains_key(uid) { continue; }
+            let task = match worktree.pod_status(uid) {
+                Some(task) => task,
+                None => { continue; }
+            };
+            pods.push(PodReport {
+                uid: uid.clone(),
+                sandbox_id: String::new(),
+                generation: podconfig.config.generation,
+                restart_count: state.next_pod_attempt(uid),
+                ready: false,
+                containers: vec![],
+                last_error: task.last_error(),
+                task: Some(task.to_string()),
+            });
+        }
+        pods.sort_by(|a, b| a.uid.cmp(&b.uid));
+        NodeStatus { node, pods }
+    }
+
+    /// Whether anything changed that is worth reporting right away: pods or containers coming and
+    /// going, or changing state. Tasks and their errors come and go with every retry, so they wait
+    /// for the next periodic report.
+    pub fn differs_significantly(&self, other: &NodeStatus) -> bool {
+        fn without_tasks(status: &NodeStatus) -> NodeStatus {
+            let mut status = status.clone();
+            for pod in status.pods.iter_mut() {
+                pod.task = None;
+                pod.last_error = None;
+                for ctr in pod.containers.iter_mut() {
+                    ctr.task = None;
+                    ctr.last_error = None;
+                }
+            }
+            status
+        }
+        without_tasks(self) != without_tasks(other)
+    }
+}
+
+async fn send_status(client: &reqwest::Client, url: &str, status: &NodeStatus) -> Result<(), Error> {
+    client.post(url)
+        .json(status)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+/// Push the node's status to the control plane periodically, and whenever it changes significantly.
+pub async fn report_status(url: String, mut status_rx: WatchRx<NodeStatus>) -> Result<(), Error> {
+    let client = reqwest::Client::builder()
+        .timeout(STATUS_REPORT_TIMEOUT)
+        .build()?;
+    let mut report_interval = tokio::time::interval(STATUS_REPORT_INTERVAL);
+    let mut last_sent: Option<NodeStatus> = None;
+    loop {
+        select! {
+            _ = report_interval.tick() => {}
+            changed = status_rx.changed() => {
+                if changed.is_err() { return Ok(()); } // The control loop is gone.
+                let significant = match last_sent {
+                    Some(ref last) => last.differs_significantly(&status_rx.borrow_and_update()),
+                    None => true,
+                };
+                if !significant { continue; }
+                tokio::time::sleep(STATUS_SETTLE_INTERVAL).await;
+            }
+        }
+        let status = status_rx.borrow_and_update().clone();
+        match send_status(&client, &url, &status).await {
+            Ok(()) => { last_sent = Some(status); }
+            Err(e) => log_err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::UnmanagedPolicy;
+    use crate::state::tests::{container, ctr_config, exited, new_state, pod_config, sandbox};
+
+    #[test]
+    fn snapshot_reports_every_container() {
+        use cri::ContainerState as CS;
+        let mut state = new_state(UnmanagedPolicy::Ignore);
+        state.ingest(
+            vec![
+                container("c2", "p1", "sidecar", CS::ContainerExited, 0),
+                container("c1", "p1", "app", CS::ContainerRunning, 3),
+            ],
+            vec![sandbox("p1", "uid-1")],
+        );
+        let status = NodeStatus::snapshot("node-a".to_owned(), &state, &Target::new(), &WorkTree::new(Default::default()));
+        assert_eq!(status.node, "node-a");
+        assert_eq!(status.pods.len(), 1);
+        let pod = &status.pods[0];
+        assert_eq!((pod.uid.as_str(), pod.sandbox_id.as_str(), pod.generation), ("uid-1", "p1", 1));
+        let names: Vec<&str> = pod.containers.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, vec!["app", "sidecar"]);
+        assert_eq!(pod.containers[0].state, "running");
+        assert_eq!(pod.containers[0].restart_count, 3);
+        assert_eq!(pod.containers[1].state, "exited");
+    }
+
+    #[tokio::test]
+    async fn pods_that_cannot_be_created_are_reported() {
+        use crate::tasks::{Backoff, RestartPolicy, Task};
+        let mut target = Target::new();
+        target.pods.insert("uid-1".to_owned(), pod_config("uid-1", &["app"]));
+        let finished = Arc::new(tokio::sync::Notify::new());
+        let ctor = || async { Err::<(), _>(tonic::Status::already_exists("name \"web\" is reserved")) };
+        let backoff = Backoff { initial: Duration::from_secs(1), multiplier: 1.0, max: Duration::from_secs(1), jitter: false };
+        let task = Task::spawn(ctor, RestartPolicy::MaxAttempts(1), backoff, finished.clone());
+        finished.notified().await;
+        let mut worktree = WorkTree::new(Default::default());
+        worktree.insert_pod_task("uid-1".to_owned(), task);
+
+        let status = NodeStatus::snapshot("node-a".to_owned(), &new_state(UnmanagedPolicy::Ignore), &target, &worktree);
+        assert_eq!(status.pods.len(), 1);
+        let pod = &status.pods[0];
+        assert_eq!((pod.uid.as_str(), pod.sandbox_id.as_str(), pod.containers.len()), ("uid-1", "", 0));
+        assert!(pod.last_error.as_ref().unwrap().contains("is reserved"));
+    }
+
+    #[test]
+    fn errors_alone_are_not_significant() {
+        use cri::ContainerState as CS;
+        let mut state = new_state(UnmanagedPolicy::Ignore);
+        state.ingest(vec![container("c1", "p1", "app", CS::ContainerRunning, 0)], vec![sandbox("p1", "uid-1")]);
+        let before = NodeStatus::snapshot("node-a".to_owned(), &state, &Target::new(), &WorkTree::new(Default::default()));
+
+        let mut erroring = before.clone();
+        erroring.pods[0].containers[0].last_error = Some("image pull failed".to_owned());
+        assert!(!before.differs_significantly(&erroring));
+
+        state.ingest(vec![container("c1", "p1", "app", CS::ContainerExited, 0)], vec![sandbox("p1", "uid-1")]);
+        let after = NodeStatus::snapshot("node-a".to_owned(), &state, &Target::new(), &WorkTree::new(Default::default()));
+        assert!(before.differs_significantly(&after));
+    }
+
+    #[test]
+    fn finished_jobs_are_reported_as_such() {
+        use cri::ContainerState as CS;
+        let mut config = ctr_config("migrate");
+        config.run_mode = RunMode::Job;
+        config.backoff_limit = 0;
+        let mut target = Target::new();
+        target.pods.insert("uid-1".to_owned(), pod_config("uid-1", &[]));
+        target.pods.get_mut("uid-1").unwrap().containers.insert("migrate".to_owned(), config);
+
+        let mut state = new_state(UnmanagedPolicy::Ignore);
+        state.ingest(vec![container("c1", "p1", "migrate", CS::ContainerExited, 0)], vec![sandbox("p1", "uid-1")]);
+        let status = NodeStatus::snapshot("node-a".to_owned(), &state, &target, &WorkTree::new(Default::default()));
+        assert_eq!(status.pods[0].containers[0].state, "exited");
+
+        state.set_exit(&exited("c1", 0));
+        let status = NodeStatus::snapshot("node-a".to_owned(), &state, &target, &WorkTree::new(Default::default()));
+        assert_eq!(status.pods[0].containers[0].state, "succeeded");
+    }
+
+    #[test]
+    fn readiness_is_significant() {
+        use cri::ContainerState as CS;
+        use crate::prober::{ProbeKind, ProbeResult};
+        let mut target = Target::new();
+        target.pods.insert("uid-1".to_owned(), pod_config("uid-1", &["app"]));
+        let mut state = new_state(UnmanagedPolicy::Ignore);
+        state.ingest(vec![container("c1", "p1", "app", CS::ContainerRunning, 0)], vec![sandbox("p1", "uid-1")]);
+        state.update_readiness(&target, &Default::default());
+        let before = NodeStatus::snapshot("node-a".to_owned(), &state, &target, &WorkTree::new(Default::default()));
+        assert!(before.pods[0].ready && before.pods[0].containers[0].ready);
+
+        state.record_probe(ProbeResult { id: "c1".to_owned(), kind: ProbeKind::Readiness, passing: false });
+        target.pods.get_mut("uid-1").unwrap().containers.get_mut("app").unwrap().readiness_probe = Some(crate::runtime::Probe {
+            check: crate::runtime::Check::TcpSocket { port: 80 },
+            initial_delay: 0,
+            period: 10,
+            timeout: 1,
+            failure_threshold: 3,
+        });
+        state.update_readiness(&target, &Default::default());
+        let after = NodeStatus::snapshot("node-a".to_owned(), &state, &target, &WorkTree::new(Default::default()));
+        assert!(!after.pods[0].ready && !after.pods[0].containers[0].ready);
+        assert!(before.differs_significantly(&after));
+    }
+}
@@ -1,9 +1,12 @@
+use std::collections::hash_map::RandomState;
 use std::future::Future;
+use std::hash::BuildHasher;
 use tokio::select;
+use tokio::sync::Notify;
+use tokio::sync::watch;
 use crate::common::*;
 
 // TODO: Use in conjunction with ATTEMPTS metadata
-// TODO: Exponential backoff
 #[derive(PartialEq, Eq, Hash, Clone, Copy)]
 pub enum RestartPolicy {
     Always,
@@ -11,18 +14,101 @@ pub enum RestartPolicy {
     Never
 }
 
+/// How long to wait before retrying a failed attempt. The delay starts at `initial` and is
+/// multiplied by `multiplier` after every failure, up to `max`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub multiplier: f64,
+    pub max: Duration,
+    /// Wait anywhere between zero and the delay instead, so that tasks that fail together
+    /// (e.g. because containerd is overloaded) don't all retry together.
+    pub jitter: bool,
+}
+
+impl Backoff {
+    /// The delay before retry number `retry`, counting from 0, before any jitter.
+    pub fn delay(&self, retry: u32) -> Duration {
+        let secs = self.initial.as_secs_f64() * self.multiplier.powi(retry.min(i32::MAX as u32) as i32);
+        Duration::from_secs_f64(secs.min(self.max.as_secs_f64()))
+    }
+
+    fn jittered(&self, retry: u32) -> Duration {
+        let delay = self.delay(retry);
+        if !self.jitter { return delay; }
+        // Good enough randomness without pulling in a crate for it.
+        let random = RandomState::new().hash_one(std::time::Instant::now());
+        delay.mul_f64(random as f64 / u64::MAX as f64)
+    }
+}
+
+/// Where a Task is at.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TaskStatus {
+    Pending,
+    /// Attempts count from 1. If the previous attempt failed, its error is kept until this one finishes.
+    Running { attempt: u64, last_error: Option<String> },
+    Succeeded,
+    /// Ran out of attempts.
+    Failed { attempts: u64, error: String },
+}
+
+impl TaskStatus {
+    /// The error from the most recent failed attempt, if any.
+    pub fn last_error(&self) -> Option<String> {
+        match self {
+            TaskStatus::Running { last_error, .. } => last_error.clone(),
+            TaskStatus::Failed { error, .. } => Some(error.clone()),
+            TaskStatus::Pending | TaskStatus::Succeeded => None,
+        }
+    }
+}
+
+impl std::fmt::Display for TaskStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TaskStatus::Pending => write!(f, "pending"),
+            TaskStatus::Running { attempt, .. } => write!(f, "running, attempt {}", attempt),
+            TaskStatus::Succeeded => write!(f, "succeeded"),
+            TaskStatus::Failed { attempts, .. } => write!(f, "failed after {} attempts", attempts),
+        }
+    }
+}
+
 type CancelToken = tokio::sync::oneshot::Sender<()>;
 
 // Supervisor for an ongoing CRI operation.
 // Cancels the operation on drop.
 pub struct Task {
     handle: tokio::task::JoinHandle<()>,
-    cancel: Option<CancelToken>
+    cancel: Option<CancelToken>,
+    status: watch::Receiver<TaskStatus>,
 }
 
 impl Task {
     // Need a ctor to produce a future to enable retries.
-    pub fn spawn<Ctor, F, E, T>(mut ctor: Ctor, restart_policy: RestartPolicy, retry_interval_ms: u64) -> Task 
+    // `finished` is notified when the task succeeds or gives up, but not when it's cancelled.
+    pub fn spawn<Ctor, F, E, T>(ctor: Ctor, restart_policy: RestartPolicy, backoff: Backoff, finished: Arc<Notify>) -> Task 
+        where F: Future<Output = Result<T, E>> + Send + 'static,
+              Ctor: FnMut() -> F + Send + 'static,
+              E: Send + std::error::Error + 'static,
+              T: Send + 'static,
+    {
+        Task::spawn_inner(ctor, restart_policy, backoff, finished, false)
+    }
+
+    // Like spawn, but cancelling lets the attempt in flight run to completion (without retrying it).
+    // For operations that shouldn't be cut short, like stopping a container during its grace period.
+    pub fn spawn_draining<Ctor, F, E, T>(ctor: Ctor, restart_policy: RestartPolicy, backoff: Backoff, finished: Arc<Notify>) -> Task 
+        where F: Future<Output = Result<T, E>> + Send + 'static,
+              Ctor: FnMut() -> F + Send + 'static,
+              E: Send + std::error::Error + 'static,
+              T: Send + 'static,
+    {
+        Task::spawn_inner(ctor, restart_policy, backoff, finished, true)
+    }
+
+    fn spawn_inner<Ctor, F, E, T>(mut ctor: Ctor, restart_policy: RestartPolicy, backoff: Backoff, finished: Arc<Notify>, drain: bool) -> Task 
         where F: Future<Output = Result<T, E>> + Send + 'static,
               Ctor: FnMut() -> F + Send + 'static,
               E: Send + std::error::Error + 'static,
@@ -37,34 +123,59 @@ impl Task {
         };
 
         let (cancel_tx, mut cancel_rx) = tokio::sync::oneshot::channel::<()>();
+        let (status_tx, status_rx) = watch::channel(TaskStatus::Pending);
         let supervisor = async move {
+            let mut last_error = None;
             loop {
+                status_tx.send_replace(TaskStatus::Running { attempt: attempts + 1, last_error: last_error.take() });
                 let mut request_handle = tokio::spawn(ctor());
-                select! {
+                let error = select! {
                     _ = &mut cancel_rx => {
-                        request_handle.abort();
-                        break;
+                        // Dropping the handle detaches the request, which then runs on its own.
+                        if !drain { request_handle.abort(); }
+                        return;
                     }
                     result = &mut request_handle => {
                         match result {
-                            Ok(Ok(_)) => { break; }  // successfully completed
-                            Ok(Err(e)) => {
-                                log_err(e);           // operation failed...
-                            }  
+                            Ok(Ok(_)) => {            // successfully completed
+                                status_tx.send_replace(TaskStatus::Succeeded);
+                                break;
+                            }
+                            Ok(Err(e)) => {           // operation failed...
+                                let error = e.to_string();
+                                log_err(e);
+                                error
+                            }
                             Err(e) => {               // thread panicked
+                                let error = e.to_string();
                                 log_err(e);       // todo: wrap errors
+                                error
                             }
                         }
                     }
-                }
+                };
                 attempts += 1;
-                if attempts >= attempt_max { break; }
-                tokio::time::sleep(Duration::from_millis(retry_interval_ms)).await;
+                if attempts >= attempt_max {
+                    status_tx.send_replace(TaskStatus::Failed { attempts, error });
+                    break;
+                }
+                status_tx.send_replace(TaskStatus::Running { attempt: attempts, last_error: Some(error.clone()) });
+                last_error = Some(error);
+                // Nothing's in flight while backing off, so there's nothing to drain either.
+                select! {
+                    _ = &mut cancel_rx => { return; }
+                    _ = tokio::time::sleep(backoff.jittered((attempts - 1).min(u32::MAX as u64) as u32)) => {}
+                }
             }
+            finished.notify_one();
         };
         let supervisor_handle = tokio::spawn(supervisor);
         
-        Task { handle: supervisor_handle, cancel: Some(cancel_tx) }
+        Task { handle: supervisor_handle, cancel: Some(cancel_tx), status: status_rx }
+    }
+
+    pub fn status(&self) -> TaskStatus {
+        self.status.borrow().clone()
     }
     
     pub fn cancel(&mut self) {
@@ -79,4 +190,108 @@ impl Drop for Task {
     fn drop(&mut self) {
         self.cancel();
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use tokio::time::Instant;
+
+    fn backoff(jitter: bool) -> Backoff {
+        Backoff { initial: Duration::from_secs(1), multiplier: 2.0, max: Duration::from_secs(5), jitter }
+    }
+
+    #[test]
+    fn delay_grows_up_to_max() {
+        let delays: Vec<u64> = (0..6).map(|retry| backoff(false).delay(retry).as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 5, 5, 5]);
+        assert_eq!(backoff(false).delay(u32::MAX), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn jitter_stays_within_delay() {
+        for retry in 0..10 {
+            assert!(backoff(true).jittered(retry) <= backoff(true).delay(retry));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_follow_backoff() {
+        let start = Instant::now();
+        let attempts = Arc::new(Mutex::new(vec![]));
+        let seen = attempts.clone();
+        let ctor = move || {
+            seen.lock().unwrap().push(start.elapsed().as_secs());
+            async { Err::<(), _>(tonic::Status::unavailable("containerd is busy")) }
+        };
+        let mut task = Task::spawn(ctor, RestartPolicy::MaxAttempts(5), backoff(false), Arc::new(Notify::new()));
+        (&mut task.handle).await.unwrap();
+        assert_eq!(*attempts.lock().unwrap(), vec![0, 1, 3, 7, 12]);
+        assert!(matches!(task.status(), TaskStatus::Failed { attempts: 5, .. }));
+        assert!(task.status().last_error().unwrap().contains("containerd is busy"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn status_follows_attempts() {
+        let finished = Arc::new(Notify::new());
+        let (go_tx, go_rx) = watch::channel(0);
+        let ctor = move || {
+            let mut go_rx = go_rx.clone();
+            async move {
+                // Fail the first attempt, then succeed once allowed to.
+                let attempt = *go_rx.borrow_and_update();
+                go_rx.changed().await.unwrap();
+                if attempt == 0 { Err(tonic::Status::not_found("no such image")) } else { Ok(()) }
+            }
+        };
+        let task = Task::spawn(ctor, RestartPolicy::Always, backoff(false), finished.clone());
+        tokio::task::yield_now().await;
+        assert_eq!(task.status(), TaskStatus::Running { attempt: 1, last_error: None });
+
+        go_tx.send_replace(1);
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        match task.status() {
+            TaskStatus::Running { attempt: 2, last_error: Some(error) } => assert!(error.contains("no such image")),
+            status => panic!("expected a second attempt, got {:?}", status),
+        }
+
+        go_tx.send_replace(2);
+        finished.notified().await;
+        assert_eq!(task.status(), TaskStatus::Succeeded);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn draining_task_finishes_its_attempt() {
+        let (done_tx, mut done_rx) = watch::channel(false);
+        let ctor = move || {
+            let done_tx = done_tx.clone();
+            async move {
+                tokio::time::sleep(Duration::from_secs(30)).await;
+                done_tx.send_replace(true);
+                Ok::<_, tonic::Status>(())
+            }
+        };
+        let task = Task::spawn_draining(ctor, RestartPolicy::Always, backoff(false), Arc::new(Notify::new()));
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        drop(task);
+        done_rx.changed().await.unwrap();
+        assert!(*done_rx.borrow());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancelled_task_does_not_retry() {
+        let attempts = Arc::new(Mutex::new(0));
+        let seen = attempts.clone();
+        let ctor = move || {
+            *seen.lock().unwrap() += 1;
+            async { Err::<(), _>(tonic::Status::unavailable("containerd is busy")) }
+        };
+        let task = Task::spawn_draining(ctor, RestartPolicy::Always, backoff(false), Arc::new(Notify::new()));
+        // Cancelled while backing off after the first attempt.
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        drop(task);
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert_eq!(*attempts.lock().unwrap(), 1);
+    }
+}
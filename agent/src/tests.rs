@@ -5,14 +5,17 @@ async fn setup_teardown() {
     fn make_uid() -> String {
         return "123456789".to_owned();
     }
-    let mut rsc = RuntimeClient::connect().await.unwrap();
+    let mut rsc = RuntimeClient::connect(node_name(), std::env::temp_dir().join("hyphae-tests")).await.unwrap();
 
     let uid = make_uid();
     let sandbox_config = SandBoxConfig {
         name: "testpod".to_owned(),
         uid: uid.clone(),
         namespace: "default".to_owned(),
-        resources: None,
+        resources: Default::default(),
+        attempt: 0,
+        generation: 0,
+        volumes: vec![],
     };
     let pod_id = rsc.create_sandbox(sandbox_config.clone()).await.unwrap();
 
@@ -23,13 +26,65 @@ async fn setup_teardown() {
         args: vec!["-c".to_owned(), "while true; do sleep 1; done".to_owned()],
         working_dir: "".to_owned(),
         envs: vec![],
-        privileged: false
+        volume_mounts: vec![],
+        resources: Default::default(),
+        privileged: false,
+        run_mode: RunMode::Service,
+        sidecar: false,
+        backoff_limit: 0,
+        grace_period: 0,
+        stop_signal: None,
+        lifecycle: Default::default(),
+        depends_on: vec![],
+        liveness_probe: None,
+        readiness_probe: None,
+        startup_probe: None,
+        attempt: 0,
     };
-    let cid = rsc.create_container(pod_id.clone(), container_config, sandbox_config).await.unwrap();
+    let cid = rsc.create_container(pod_id.clone(), container_config, sandbox_config, Default::default()).await.unwrap();
     rsc.start_container(cid).await.unwrap();
     let _ = rsc.remove_pod(pod_id.clone()).await.unwrap();
 }
 
+#[tokio::test]
+async fn post_start_hooks_outlive_their_step() {
+    use state::{ContainerStep, Plan, PodStep};
+    let mut rsc = RuntimeClient::connect(node_name(), std::env::temp_dir().join("hyphae-tests")).await.unwrap();
+    let sandbox_config = SandBoxConfig {
+        name: "hookpod".to_owned(),
+        uid: "post-start-hook".to_owned(),
+        namespace: "default".to_owned(),
+        resources: Default::default(),
+        attempt: 0,
+        generation: 0,
+        volumes: vec![],
+    };
+    let pod_id = rsc.create_sandbox(sandbox_config.clone()).await.unwrap();
+    let config = make_alpine_config("app");
+    rsc.pull_image(config.image.clone()).await.unwrap();
+    let cid = rsc.create_container(pod_id.clone(), config, sandbox_config, Default::default()).await.unwrap();
+
+    // A hook that fails, but only after the container has long shown up as running.
+    let hook = runtime::Hook::Exec { command: vec!["ash".to_owned(), "-c".to_owned(), "sleep 5; exit 1".to_owned()] };
+    let start = ContainerStep::StartCtr(cid.clone(), Some(hook));
+    let plan = |steps: Vec<(Name, ContainerStep)>| Plan {
+        pods: HashMap::from([("post-start-hook".to_owned(), PodStep::ChangePod(steps.into_iter().collect()))]),
+        unmanaged: HashMap::new(),
+        wake_at: None,
+    };
+    let worktree = worktree::WorkTree::new(worktree::WaitPolicy::default());
+    let worktree = worktree::execute(plan(vec![("app".to_owned(), start)]), worktree, &mut rsc);
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    // Running, so there's nothing left to plan for it.
+    let worktree = worktree::execute(plan(vec![]), worktree, &mut rsc);
+
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    assert!(worktree.hook_error(&cid).unwrap().starts_with("PostStart hook failed"));
+    let status = rsc.container_status(cid).await.unwrap();
+    assert_eq!(state::to_state(status.state), cri::ContainerState::ContainerExited);
+    rsc.remove_pod(pod_id).await.unwrap();
+}
+
 fn make_alpine_config(name: &str) -> ContainerConfig {
     let container_config = ContainerConfig {
         name: name.to_owned(),
@@ -38,7 +93,20 @@ fn make_alpine_config(name: &str) -> ContainerConfig {
         args: vec!["-c".to_owned(), "while true; do sleep 1; done".to_owned()],
         working_dir: "".to_owned(),
         envs: vec![],
-        privileged: false
+        volume_mounts: vec![],
+        resources: Default::default(),
+        privileged: false,
+        run_mode: RunMode::Service,
+        sidecar: false,
+        backoff_limit: 0,
+        grace_period: 0,
+        stop_signal: None,
+        lifecycle: Default::default(),
+        depends_on: vec![],
+        liveness_probe: None,
+        readiness_probe: None,
+        startup_probe: None,
+        attempt: 0,
     };
     container_config
 }
@@ -54,14 +122,14 @@ async fn test_agent() {
             let uid = format!("#{}", i);
             let name = format!("pod{}", i);
             let config = SandBoxConfig {
-                name, uid: uid.clone(), namespace: "default".to_owned(), resources: None
+                name, uid: uid.clone(), namespace: "default".to_owned(), resources: Default::default(), attempt: 0, generation: 0, volumes: vec![]
             };
             let mut containers = HashMap::new();
             for i in 0..num_containers {
                 let name = format!("alpine{}", i);
                 containers.insert(name.clone(), make_alpine_config(&name));
             }
-            target.pods.insert(uid, PodConfig { config, containers });
+            target.pods.insert(uid, PodConfig { restart_policy: Default::default(), config, containers, init_containers: vec![] });
         }
 
         target_tx.send(target).unwrap();
@@ -69,14 +137,15 @@ async fn test_agent() {
             tokio::time::sleep(Duration::from_millis(1_000)).await;
         }
     }
-    let runtime = RuntimeClient::connect().await.expect("Could not connect to containerd.");
+    let runtime = RuntimeClient::connect(node_name(), std::env::temp_dir().join("hyphae-tests")).await.expect("Could not connect to containerd.");
     let mut set = JoinSet::new();
     let (events_tx, events_rx) = tokio::sync::mpsc::channel(EVENTS_BUFFER_MAX);
     let (target_tx, target_rx) = tokio::sync::watch::channel(state::Target::new());
+    let (status_tx, _status_rx) = tokio::sync::watch::channel(status::NodeStatus::new(node_name()));
 
     set.spawn(poll_for_target(target_tx));
     set.spawn(read_events(runtime.clone(), events_tx));
-    set.spawn(control_loop(runtime.clone(), events_rx, target_rx));
+    set.spawn(control_loop(runtime.clone(), events_rx, target_rx, status_tx, state::UnmanagedPolicy::Ignore, worktree::WaitPolicy::default()));
 
     let results = set.join_all().await;
     for result in results {
@@ -85,4 +154,161 @@ async fn test_agent() {
         }
     }
     
-}
\ No newline at end of file
+}
+// A stand-in for the control plane: answers every request with the same JSON body.
+async fn serve_json(body: String) -> std::net::SocketAddr {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let body = body.clone();
+            tokio::spawn(async move {
+                let mut buf = [0u8; 4096];
+                let _ = stream.read(&mut buf).await;
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    body.len(), body
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            });
+        }
+    });
+    addr
+}
+
+const TARGET_JSON: &'static str = r#"{
+    "api_version": "hyphae/v1",
+    "pods": [
+        {
+            "sandbox": { "name": "web", "uid": "uid-1" },
+            "containers": {
+                "nginx": {
+                    "image": "docker.io/library/nginx:latest",
+                    "command": "nginx",
+                    "args": ["-g", "daemon off;"],
+                    "envs": [{ "name": "PORT", "value": "80" }]
+                }
+            }
+        }
+    ]
+}"#;
+
+#[tokio::test]
+async fn fetch_target_decodes_json() {
+    let addr = serve_json(TARGET_JSON.to_owned()).await;
+    let client = reqwest::Client::new();
+    let target = fetch_target(&client, &format!("http://{}/", addr)).await.unwrap();
+
+    let pod = target.pods.get("uid-1").unwrap();
+    assert_eq!(pod.config.name, "web");
+    let nginx = pod.containers.get("nginx").unwrap();
+    assert_eq!(nginx.image, "docker.io/library/nginx:latest");
+    assert_eq!(nginx.envs, vec![runtime::EnvVar::new("PORT", "80")]);
+}
+
+#[tokio::test]
+async fn poll_for_target_publishes_changes_only() {
+    let addr = serve_json(TARGET_JSON.to_owned()).await;
+    let (target_tx, mut target_rx) = tokio::sync::watch::channel(state::Target::new());
+    let poller = tokio::spawn(poll_for_target(format!("http://{}/", addr), target_tx.clone()));
+
+    tokio::time::timeout(Duration::from_secs(5), target_rx.changed()).await.unwrap().unwrap();
+    let target = target_rx.borrow_and_update().clone();
+    assert!(target.pods.contains_key("uid-1"));
+    poller.abort();
+
+    assert!(!publish_target(&target_tx, target.clone()));
+    assert!(!target_rx.has_changed().unwrap());
+    assert!(publish_target(&target_tx, state::Target::new()));
+    assert!(target_rx.has_changed().unwrap());
+}
+
+#[tokio::test]
+async fn static_pods_are_merged_into_the_target() {
+    let mut remote = manifest::parse_target(TARGET_JSON, manifest::Format::Json).unwrap();
+    let mut web = remote.pods.get("uid-1").unwrap().clone();
+    let mut static_pods = state::Target::new();
+    web.config.name = "static-web".to_owned();
+    static_pods.pods.insert("uid-1".to_owned(), web.clone());
+    web.config.name = "proxy".to_owned();
+    static_pods.pods.insert("uid-2".to_owned(), web.clone());
+    web.config.name = "api".to_owned();
+    remote.pods.insert("uid-3".to_owned(), web);
+
+    let (static_tx, static_rx) = tokio::sync::watch::channel(state::Target::new());
+    let (remote_tx, remote_rx) = tokio::sync::watch::channel(state::Target::new());
+    let (target_tx, mut target_rx) = tokio::sync::watch::channel(state::Target::new());
+    let combiner = tokio::spawn(combine_targets(static_rx, remote_rx, target_tx));
+
+    static_tx.send(static_pods).unwrap();
+    tokio::time::timeout(Duration::from_secs(5), target_rx.changed()).await.unwrap().unwrap();
+    assert_eq!(target_rx.borrow_and_update().pods.len(), 2);
+
+    // The static manifest wins a uid collision, and static pods outlive the control plane.
+    remote_tx.send(remote).unwrap();
+    drop(remote_tx);
+    tokio::time::timeout(Duration::from_secs(5), target_rx.changed()).await.unwrap().unwrap();
+    let target = target_rx.borrow_and_update().clone();
+    assert_eq!(target.pods.get("uid-1").unwrap().config.name, "static-web");
+    assert!(target.pods.contains_key("uid-2"));
+    assert!(target.pods.contains_key("uid-3"));
+
+    drop(static_tx);
+    combiner.await.unwrap().unwrap();
+    assert_eq!(target_rx.borrow().pods.len(), 3);
+}
+
+// A stand-in for the control plane's status endpoint: hands over the body of every request it gets.
+async fn capture_posts() -> (std::net::SocketAddr, tokio::sync::mpsc::Receiver<String>) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (bodies_tx, bodies_rx) = tokio::sync::mpsc::channel(16);
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let bodies_tx = bodies_tx.clone();
+            tokio::spawn(async move {
+                let mut request = vec![];
+                let mut buf = [0u8; 4096];
+                let body = loop {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&request).to_string();
+                    if let Some(end) = text.find("\r\n\r\n") {
+                        let length = text.lines()
+                            .find_map(|line| line.to_lowercase().strip_prefix("content-length:").map(|l| l.trim().parse::<usize>().unwrap()))
+                            .unwrap_or(0);
+                        if request.len() >= end + 4 + length {
+                            break text[end + 4..].to_owned();
+                        }
+                    }
+                };
+                let _ = stream.write_all(b"HTTP/1.1 204 No Content\r\nconnection: close\r\n\r\n").await;
+                let _ = bodies_tx.send(body).await;
+            });
+        }
+    });
+    (addr, bodies_rx)
+}
+
+#[tokio::test]
+async fn report_status_pushes_changes() {
+    let (addr, mut bodies) = capture_posts().await;
+    let (status_tx, status_rx) = tokio::sync::watch::channel(status::NodeStatus::new("node-a".to_owned()));
+    let reporter = tokio::spawn(status::report_status(format!("http://{}/", addr), status_rx));
+
+    // The first report goes out right away.
+    let first = tokio::time::timeout(Duration::from_secs(5), bodies.recv()).await.unwrap().unwrap();
+    assert_eq!(first, r#"{"node":"node-a","pods":[]}"#);
+
+    status_tx.send(status::NodeStatus {
+        node: "node-a".to_owned(),
+        pods: vec![status::PodReport { uid: "uid-1".to_owned(), sandbox_id: "p1".to_owned(), generation: 1, restart_count: 0, ready: false, containers: vec![], task: None, last_error: None }],
+    }).unwrap();
+    let second = tokio::time::timeout(Duration::from_secs(5), bodies.recv()).await.unwrap().unwrap();
+    assert!(second.contains(r#""uid":"uid-1""#));
+    reporter.abort();
+}
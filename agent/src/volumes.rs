@@ -0,0 +1,307 @@
+//! Pods' volumes on the node. Everything but host paths lives in a directory per sandbox under the
+//! agent's data root, created along with the sandbox and removed with it.
+use std::io;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::SystemTime;
+use crate::common::*;
+use crate::runtime::{Volume, VolumeMount};
+use crate::state::{Files, State, Target};
+
+pub const DEFAULT_DATA_ROOT: &'static str = "/var/lib/hyphae";
+const MOUNT_TABLE_PATH: &'static str = "/proc/self/mounts";
+// A config or secret volume's files are symlinks through this to the directory holding the current version.
+const DATA_LINK: &'static str = "..data";
+// Written into a sandbox's directory once all of its volumes are there.
+const CREATED_MARKER: &'static str = "created";
+const CONFIG_FILE_MODE: u32 = 0o644;
+// Readable by whatever user the containers run as, like the kubelet's default for secret volumes.
+// The volume is only mounted into the pod's own containers, read-only.
+const SECRET_FILE_MODE: u32 = 0o444;
+
+// Keyed by sandbox rather than pod, so that a recreated sandbox starts out with empty volumes.
+fn sandbox_dir(data_root: &Path, pod_id: &str) -> PathBuf {
+    data_root.join("sandboxes").join(pod_id)
+}
+
+fn host_path(data_root: &Path, pod_id: &str, volume: &Volume) -> PathBuf {
+    match volume {
+        Volume::HostPath { path, .. } => PathBuf::from(path),
+        _ => sandbox_dir(data_root, pod_id).join("volumes").join(volume.name()),
+    }
+}
+
+/// Create the volumes of a newly created sandbox.
+pub async fn create(data_root: &Path, pod_id: &str, volumes: &[Volume]) -> io::Result<()> {
+    let (data_root, pod_id, volumes) = (data_root.to_owned(), pod_id.to_owned(), volumes.to_vec());
+    blocking(move || {
+        std::fs::create_dir_all(sandbox_dir(&data_root, &pod_id))?;
+        for volume in volumes.iter() {
+            let path = host_path(&data_root, &pod_id, volume);
+            match volume {
+                // Writable by whatever user the containers run as, like tmpfs' mode below.
+                Volume::EmptyDir { .. } => {
+                    std::fs::create_dir_all(&path)?;
+                    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o777))?;
+                }
+                Volume::Tmpfs { size_mb, .. } => {
+                    let mut options = "mode=0777".to_owned();
+                    if let Some(size) = size_mb {
+                        options += &format!(",size={}m", size);
+                    }
+                    mount_tmpfs(&path, &options)?;
+                }
+                // Filled in by Projections once the sandbox is up.
+                Volume::Config { .. } => std::fs::create_dir_all(&path)?,
+                Volume::Secret { .. } => mount_tmpfs(&path, "mode=0755")?,
+                Volume::HostPath { .. } => {}
+            }
+        }
+        std::fs::write(sandbox_dir(&data_root, &pod_id).join(CREATED_MARKER), "")
+    }).await
+}
+
+/// Whether a sandbox's volumes are all there. The sandbox shows up before they are, and anything
+/// that used them by then would find (or make) plain directories in their place.
+pub fn created(data_root: &Path, pod_id: &str, volumes: &[Volume]) -> bool {
+    let nothing_to_create = volumes.iter().all(|volume| matches!(volume, Volume::HostPath { .. }));
+    nothing_to_create || sandbox_dir(data_root, pod_id).join(CREATED_MARKER).exists()
+}
+
+/// Remove everything a sandbox had on the node. Fine to call for sandboxes that never had any volumes.
+pub async fn remove(data_root: &Path, pod_id: &str) -> io::Result<()> {
+    let dir = sandbox_dir(data_root, pod_id);
+    blocking(move || {
+        // Anything still mounted would be removed along with the directory, host paths included.
+        let table = std::fs::read_to_string(MOUNT_TABLE_PATH)?;
+        for mount_point in mounts_under(&dir, &table) {
+            run(Command::new("umount").arg(&mount_point))?;
+        }
+        match std::fs::remove_dir_all(&dir) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }).await
+}
+
+/// What to mount into a container, given its pod's volumes.
+pub fn mounts(data_root: &Path, pod_id: &str, volumes: &[Volume], volume_mounts: &[VolumeMount])
+    -> Result<Vec<cri::Mount>, String>
+{
+    volume_mounts.iter().map(|mount| {
+        let volume = volumes.iter()
+            .find(|volume| volume.name() == mount.name)
+            .ok_or_else(|| format!("The pod has no volume named {:?}", mount.name))?;
+        let read_only = match volume {
+            Volume::HostPath { read_only: true, .. } | Volume::Config { .. } | Volume::Secret { .. } => true,
+            _ => mount.read_only,
+        };
+        Ok(cri::Mount {
+            container_path: mount.mount_path.clone(),
+            host_path: host_path(data_root, pod_id, volume).to_string_lossy().into_owned(),
+            readonly: read_only,
+            ..Default::default()
+        })
+    }).collect()
+}
+
+/// The mount points at or under `dir` in a table like /proc/self/mounts, deepest first.
+fn mounts_under(dir: &Path, table: &str) -> Vec<PathBuf> {
+    let mut mount_points: Vec<PathBuf> = table.lines()
+        .filter_map(|line| line.split(' ').nth(1))
+        .map(|mount_point| PathBuf::from(mount_point.replace("\\040", " ")))
+        .filter(|mount_point| mount_point.starts_with(dir))
+        .collect();
+    mount_points.sort_by_key(|mount_point| std::cmp::Reverse(mount_point.components().count()));
+    mount_points.dedup();
+    mount_points
+}
+
+/// Keeps the files of pods' config and secret volumes in line with the target.
+pub struct Projections {
+    /// What was last written to each volume, by sandbox and volume name.
+    written: HashMap<(PodId, Name), Files>,
+}
+
+impl Projections {
+    pub fn new() -> Projections {
+        Projections { written: HashMap::new() }
+    }
+
+    /// Write out the files of every config and secret volume whose contents have changed since they
+    /// were last written, or that haven't been written yet.
+    pub async fn sync(&mut self, data_root: &Path, state: &State, target: &Target) {
+        let mut wanted = HashMap::new();
+        for (uid, pod) in state.pods.iter() {
+            let podconfig = match target.pods.get(uid) {
+                Some(podconfig) => podconfig,
+                None => { continue; }
+            };
+            // Secrets written before their tmpfs is mounted would end up on disk.
+            if !created(data_root, &pod.id, &podconfig.config.volumes) { continue; }
+            for volume in podconfig.config.volumes.iter() {
+                let projected = match volume {
+                    Volume::Config { config, .. } => target.configs.get(config).map(|files| (files, CONFIG_FILE_MODE)),
+                    Volume::Secret { secret, .. } => target.secrets.get(secret).map(|files| (files, SECRET_FILE_MODE)),
+                    _ => None,
+                };
+                if let Some(projected) = projected {
+                    wanted.insert((pod.id.clone(), volume.name().to_owned()), projected);
+                }
+            }
+        }
+        self.written.retain(|key, _| wanted.contains_key(key));
+        for (key, (files, mode)) in wanted {
+            if self.written.get(&key) == Some(files) { continue; }
+            let (pod_id, name) = &key;
+            let dir = sandbox_dir(data_root, pod_id).join("volumes").join(name);
+            let contents = files.clone();
+            match blocking(move || project(&dir, &contents, mode)).await {
+                Ok(()) => { self.written.insert(key, files.clone()); }
+                Err(e) => println!("Could not write volume {} of pod {}: {}", name, pod_id, e),
+            }
+        }
+    }
+}
+
+/// Replace the files in `dir` all at once. Like the kubelet, each version goes in a new hidden directory
+/// that ..data is swapped over to with a rename, and the files themselves are symlinks through ..data, so
+/// that readers see either every old file or every new one.
+fn project(dir: &Path, files: &Files, mode: u32) -> io::Result<()> {
+    // The volume is created along with the sandbox. Creating it here could put secrets on disk.
+    if !dir.is_dir() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} does not exist yet", dir.display())));
+    }
+    let nanos = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_nanos();
+    let version = format!("..{}", nanos);
+    std::fs::create_dir(dir.join(&version))?;
+    for (name, contents) in files.iter() {
+        let path = dir.join(&version).join(name);
+        std::fs::write(&path, contents)?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode))?;
+    }
+    let new_link = dir.join(format!("{}_tmp", DATA_LINK));
+    match std::fs::remove_file(&new_link) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => { return Err(e); }
+        _ => {}
+    }
+    symlink(&version, &new_link)?;
+    std::fs::rename(&new_link, dir.join(DATA_LINK))?;
+
+    // Link new files, and clean up old files and versions.
+    for name in files.keys() {
+        let link = dir.join(name);
+        if std::fs::symlink_metadata(&link).is_err() {
+            symlink(Path::new(DATA_LINK).join(name), &link)?;
+        }
+    }
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name == DATA_LINK || name == version {
+            continue;
+        } else if name.starts_with("..") {
+            std::fs::remove_dir_all(entry.path())?;
+        } else if !files.contains_key(&name) {
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+fn mount_tmpfs(path: &Path, options: &str) -> io::Result<()> {
+    std::fs::create_dir_all(path)?;
+    run(Command::new("mount").args(["-t", "tmpfs", "-o", options, "tmpfs"]).arg(path))
+}
+
+fn run(command: &mut Command) -> io::Result<()> {
+    let output = command.output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(io::Error::new(io::ErrorKind::Other, format!("{:?} failed: {}", command, stderr.trim())));
+    }
+    Ok(())
+}
+
+// Filesystem calls and mount(8) block, so keep them off the runtime's threads.
+async fn blocking<F: FnOnce() -> io::Result<()> + Send + 'static>(f: F) -> io::Result<()> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .unwrap_or_else(|e| Err(io::Error::new(io::ErrorKind::Other, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mounts_are_removed_deepest_first() {
+        let table = "\
+/dev/sda1 / ext4 rw,relatime 0 0
+tmpfs /var/lib/hyphae/sandboxes/abc/volumes/cache tmpfs rw,size=65536k,mode=777 0 0
+tmpfs /var/lib/hyphae/sandboxes/abc/volumes/cache/nested\\040dir tmpfs rw 0 0
+tmpfs /var/lib/hyphae/sandboxes/abcd/volumes/cache tmpfs rw 0 0
+";
+        let dir = sandbox_dir(Path::new(DEFAULT_DATA_ROOT), "abc");
+        assert_eq!(mounts_under(&dir, table), vec![
+            PathBuf::from("/var/lib/hyphae/sandboxes/abc/volumes/cache/nested dir"),
+            PathBuf::from("/var/lib/hyphae/sandboxes/abc/volumes/cache"),
+        ]);
+    }
+
+    #[test]
+    fn mounts_resolve_volumes() {
+        let volumes = vec![
+            Volume::EmptyDir { name: "cache".to_owned() },
+            Volume::HostPath { name: "logs".to_owned(), path: "/var/log".to_owned(), read_only: true },
+        ];
+        let mount = |name: &str, mount_path: &str| VolumeMount { name: name.to_owned(), mount_path: mount_path.to_owned(), read_only: false };
+        let cri_mounts = mounts(Path::new("/data"), "abc", &volumes, &[mount("cache", "/cache"), mount("logs", "/logs")]).unwrap();
+        let resolved: Vec<(&str, &str, bool)> = cri_mounts.iter()
+            .map(|m| (m.container_path.as_str(), m.host_path.as_str(), m.readonly))
+            .collect();
+        assert_eq!(resolved, vec![
+            ("/cache", "/data/sandboxes/abc/volumes/cache", false),
+            ("/logs", "/var/log", true),
+        ]);
+        assert!(mounts(Path::new("/data"), "abc", &volumes, &[mount("other", "/other")]).is_err());
+    }
+
+    #[test]
+    fn projected_files_are_swapped_in() {
+        let dir = std::env::temp_dir().join(format!("hyphae-projected-{}", std::process::id()));
+        assert!(project(&dir, &Files::new(), CONFIG_FILE_MODE).is_err());
+        std::fs::create_dir_all(&dir).unwrap();
+        let files = |pairs: &[(&str, &str)]| -> Files {
+            pairs.iter().map(|(name, contents)| (name.to_string(), contents.to_string())).collect()
+        };
+        project(&dir, &files(&[("app.toml", "v1"), ("old.toml", "gone")]), SECRET_FILE_MODE).unwrap();
+        assert_eq!(std::fs::read_to_string(dir.join("app.toml")).unwrap(), "v1");
+        let mode = std::fs::metadata(dir.join("app.toml")).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o444);
+
+        project(&dir, &files(&[("app.toml", "v2"), ("new.toml", "new")]), CONFIG_FILE_MODE).unwrap();
+        assert_eq!(std::fs::read_to_string(dir.join("app.toml")).unwrap(), "v2");
+        assert_eq!(std::fs::read_to_string(dir.join("new.toml")).unwrap(), "new");
+        assert!(std::fs::symlink_metadata(dir.join("old.toml")).is_err());
+        // Just ..data and the one version it points to are left behind.
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 4);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn empty_dirs_go_with_their_sandbox() {
+        let data_root = std::env::temp_dir().join(format!("hyphae-volumes-{}", std::process::id()));
+        let volumes = vec![Volume::EmptyDir { name: "cache".to_owned() }];
+        assert!(!created(&data_root, "abc", &volumes));
+        create(&data_root, "abc", &volumes).await.unwrap();
+        assert!(created(&data_root, "abc", &volumes));
+        let path = host_path(&data_root, "abc", &volumes[0]);
+        std::fs::write(path.join("file"), "data").unwrap();
+        remove(&data_root, "abc").await.unwrap();
+        assert!(!sandbox_dir(&data_root, "abc").exists());
+        remove(&data_root, "abc").await.unwrap();
+        std::fs::remove_dir_all(&data_root).unwrap();
+    }
+}
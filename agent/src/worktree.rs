@@ -1,10 +1,71 @@
+use std::sync::Mutex;
+use tokio::sync::Notify;
+use crate::prober::run_check;
+use crate::runtime::Resources;
 use crate::{
     common::*,
     tasks::*,
     state::*,
 };
 
-const CRI_RETRY_INTERVAL_MS: u64 = 3000;
+// Creating a container includes pulling its image, which can fail for a long time, e.g. during a
+// registry outage. Everything else should clear up quickly.
+const CREATE_CTR_BACKOFF: Backoff = Backoff {
+    initial: Duration::from_secs(2),
+    multiplier: 2.0,
+    max: Duration::from_secs(120),
+    jitter: true,
+};
+const CRI_BACKOFF: Backoff = Backoff {
+    initial: Duration::from_millis(500),
+    multiplier: 2.0,
+    max: Duration::from_secs(30),
+    jitter: true,
+};
+
+// How often to check whether a container we've signalled has exited yet.
+const STOP_POLL_INTERVAL: Duration = Duration::from_secs(1);
+// PreStop hooks are bounded by the grace period instead.
+const POST_START_TIMEOUT: Duration = Duration::from_secs(30);
+
+// The last lifecycle hook failure for each container, written by the tasks running the hooks.
+type HookErrors = Arc<Mutex<HashMap<CtrId, String>>>;
+// The resources containers were updated to in place, or None if the runtime wouldn't. Written by
+// the tasks doing it, and handed over to the State by the control loop.
+type Resizes = Arc<Mutex<HashMap<CtrId, Option<Resources>>>>;
+// Containers whose PostStart hook hasn't finished yet. They aren't ready until it has.
+type PostStarts = Arc<Mutex<std::collections::HashSet<CtrId>>>;
+
+/// What to do about a container stuck in the Unknown state.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WaitPolicy {
+    pub poll_interval: Duration,
+    /// How long to give it to settle.
+    pub deadline: Duration,
+    /// Whether to force it out once the deadline has passed, by stopping it without a grace
+    /// period and removing it. Otherwise we keep waiting.
+    pub escalate: bool,
+}
+
+impl Default for WaitPolicy {
+    fn default() -> WaitPolicy {
+        WaitPolicy { poll_interval: Duration::from_secs(2), deadline: Duration::from_secs(60), escalate: true }
+    }
+}
+
+impl std::str::FromStr for WaitPolicy {
+    type Err = String;
+    /// Either the deadline in seconds, or "never" to wait forever.
+    fn from_str(s: &str) -> Result<WaitPolicy, String> {
+        match s {
+            "never" => Ok(WaitPolicy { escalate: false, ..Default::default() }),
+            secs => match secs.parse() {
+                Ok(secs) => Ok(WaitPolicy { deadline: Duration::from_secs(secs), ..Default::default() }),
+                Err(_) => Err(format!("expected a number of seconds or never, got {:?}", s)),
+            },
+        }
+    }
+}
 
 enum PodTask {
     CreatePod(Task),
@@ -16,16 +77,19 @@ enum ContainerTask {
     CreateCtr(Task),
     StartCtr(Task),
     StopCtr(Task),
+    /// Along with what it's updating them to.
+    UpdateResources(Task, Resources),
     DeleteCtr(Task),
     WaitCtr(Task), // If ctr state is unknown, let it stabilize first
 }
 
 impl ContainerTask {
-    fn into_inner(self) -> Task {
+    fn inner(&self) -> &Task {
         match self {
             ContainerTask::CreateCtr(task) => task,
             ContainerTask::StartCtr(task) => task,
             ContainerTask::StopCtr(task) => task,
+            ContainerTask::UpdateResources(task, _) => task,
             ContainerTask::DeleteCtr(task) => task,
             ContainerTask::WaitCtr(task) => task,
         }
@@ -34,12 +98,80 @@ impl ContainerTask {
 
 /// A tree of Tasks executing the aforementioned plan
 pub struct WorkTree {
-    pods: HashMap<UID, PodTask>
+    pods: HashMap<UID, PodTask>,
+    unmanaged: HashMap<PodId, PodTask>,
+    // Shared by every task in the tree, and by the trees that follow it.
+    finished: Arc<Notify>,
+    wait_policy: WaitPolicy,
+    hook_errors: HookErrors,
+    resizes: Resizes,
+    post_starts: PostStarts,
 }
 
 impl WorkTree {
-    pub fn new() -> WorkTree {
-        WorkTree { pods: HashMap::new() }
+    pub fn new(wait_policy: WaitPolicy) -> WorkTree {
+        WorkTree {
+            pods: HashMap::new(),
+            unmanaged: HashMap::new(),
+            finished: Arc::new(Notify::new()),
+            wait_policy,
+            hook_errors: Default::default(),
+            resizes: Default::default(),
+            post_starts: Default::default(),
+        }
+    }
+
+    /// Notified whenever one of the tree's tasks succeeds or gives up, which is a good time to plan again.
+    pub fn finished(&self) -> Arc<Notify> {
+        self.finished.clone()
+    }
+
+    /// The status of a pod-level task (creating or deleting the sandbox).
+    pub fn pod_status(&self, uid: &UID) -> Option<TaskStatus> {
+        match self.pods.get(uid) {
+            Some(PodTask::CreatePod(task)) | Some(PodTask::DeletePod(task)) => Some(task.status()),
+            _ => None,
+        }
+    }
+
+    /// Why the container's last lifecycle hook failed, if it did.
+    pub fn hook_error(&self, id: &CtrId) -> Option<String> {
+        self.hook_errors.lock().unwrap().get(id).cloned()
+    }
+
+    /// Forget hook failures for containers that are gone.
+    pub fn prune_hook_errors(&self, state: &State) {
+        let mut hook_errors = self.hook_errors.lock().unwrap();
+        if hook_errors.is_empty() { return; }
+        let ids: std::collections::HashSet<&CtrId> = state.pods.values()
+            .flat_map(|pod| pod.ctrs.values().map(|ctr| &ctr.id))
+            .collect();
+        hook_errors.retain(|id, _| ids.contains(id));
+    }
+
+    /// Resource updates that have finished since the last call, one way or the other.
+    pub fn take_resizes(&self) -> HashMap<CtrId, Option<Resources>> {
+        std::mem::take(&mut *self.resizes.lock().unwrap())
+    }
+
+    /// Containers that are being started, but whose PostStart hook hasn't finished yet.
+    pub fn post_starting(&self) -> std::collections::HashSet<CtrId> {
+        self.post_starts.lock().unwrap().clone()
+    }
+
+    /// The status of the task currently working on a container.
+    pub fn ctr_status(&self, uid: &UID, name: &Name) -> Option<TaskStatus> {
+        match self.pods.get(uid) {
+            Some(PodTask::ChangePod(tasks)) => tasks.get(name).map(|task| task.inner().status()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+impl WorkTree {
+    pub(crate) fn insert_pod_task(&mut self, uid: UID, task: Task) {
+        self.pods.insert(uid, PodTask::CreatePod(task));
     }
 }
 
@@ -47,45 +179,85 @@ impl WorkTree {
 /// If we were already doing the task, move the task into the new worktree.
 /// Otherwise, spawn the new task. The rest of them simply get dropped on the floor,
 /// which triggers the cancel token. 
-pub fn execute(plan: Plan, mut old_worktree: WorkTree, rsc: &mut RuntimeClient) -> WorkTree {
+pub fn execute(plan: Plan, old_worktree: WorkTree, rsc: &mut RuntimeClient) -> WorkTree {
+    let finished = old_worktree.finished;
+    let wait_policy = old_worktree.wait_policy;
+    let hook_errors = old_worktree.hook_errors;
+    let resizes = old_worktree.resizes;
+    let post_starts = old_worktree.post_starts;
+    WorkTree {
+        pods: execute_pods(plan.pods, old_worktree.pods, rsc, &finished, wait_policy, &hook_errors, &resizes, &post_starts),
+        unmanaged: execute_pods(plan.unmanaged, old_worktree.unmanaged, rsc, &finished, wait_policy, &hook_errors, &resizes, &post_starts),
+        finished,
+        wait_policy,
+        hook_errors,
+        resizes,
+        post_starts,
+    }
+}
+
+fn execute_pods(
+    steps: HashMap<String, PodStep>,
+    mut old_tasks: HashMap<String, PodTask>,
+    rsc: &mut RuntimeClient,
+    finished: &Arc<Notify>,
+    wait_policy: WaitPolicy,
+    hook_errors: &HookErrors,
+    resizes: &Resizes,
+    post_starts: &PostStarts,
+)
+    -> HashMap<String, PodTask>
+{
     use PodTask as PT;
     use PodStep as PS;
     use ContainerStep as CS;
     use ContainerTask as CT;
-    let mut new_worktree = WorkTree { pods: HashMap::new() };
-    for (uid, pod_step) in plan.pods {
-        match (pod_step, old_worktree.pods.remove(&uid)) {
+    let mut new_tasks = HashMap::new();
+    for (uid, pod_step) in steps {
+        match (pod_step, old_tasks.remove(&uid)) {
             (PS::CreatePod(_), Some(PT::CreatePod(task))) => {
-                new_worktree.pods.insert(uid.clone(), PT::CreatePod(task));
+                new_tasks.insert(uid.clone(), PT::CreatePod(task));
             }
             (PS::DeletePod(_), Some(PT::DeletePod(task))) => {
-                new_worktree.pods.insert(uid.clone(), PT::DeletePod(task));
+                new_tasks.insert(uid.clone(), PT::DeletePod(task));
             }
-            (PS::ChangePod(steps), Some(PT::ChangePod(mut old_tasks))) => {
+            (PS::ChangePod(steps), Some(PT::ChangePod(mut old_ctr_tasks))) => {
                 let tasks = steps.into_iter()
                     .map(|(name, step)| {
-                        match (step, old_tasks.remove(&name)) {
-                            (CS::WaitCtr(..), Some(ctr_task)) => (name, CT::WaitCtr(ctr_task.into_inner())),
+                        match (step, old_ctr_tasks.remove(&name)) {
+                            (CS::WaitCtr(..), Some(CT::WaitCtr(task))) => (name, CT::WaitCtr(task)),
                             (CS::CreateCtr(..), Some(CT::CreateCtr(task))) => (name, CT::CreateCtr(task)),
                             (CS::StartCtr(..), Some(CT::StartCtr(task))) => (name, CT::StartCtr(task)),
                             (CS::StopCtr(..), Some(CT::StopCtr(task))) => (name, CT::StopCtr(task)),
+                            // Only if it's still the update that's wanted.
+                            (CS::UpdateResources(_, wanted), Some(CT::UpdateResources(task, resources))) if wanted == resources => {
+                                (name, CT::UpdateResources(task, resources))
+                            }
                             (CS::DeleteCtr(..), Some(CT::DeleteCtr(task))) => (name, CT::DeleteCtr(task)),
-                            (step, _) => (name, step.spawn(rsc.clone())),
+                            (step, _) => (name, step.spawn(rsc.clone(), finished, wait_policy, hook_errors, resizes, post_starts)),
                         }
                     })
                     .collect();
-                new_worktree.pods.insert(uid.clone(), PT::ChangePod(tasks));
+                new_tasks.insert(uid.clone(), PT::ChangePod(tasks));
             }
             (pod_step, _) => {
-                new_worktree.pods.insert(uid.clone(), pod_step.spawn(rsc.clone()));
+                new_tasks.insert(uid.clone(), pod_step.spawn(rsc.clone(), finished, wait_policy, hook_errors, resizes, post_starts));
             }
         }
     }
-    new_worktree
+    new_tasks
 }
 
 impl crate::state::PodStep {
-    fn spawn(self, rsc: RuntimeClient) -> PodTask {
+    fn spawn(
+        self,
+        rsc: RuntimeClient,
+        finished: &Arc<Notify>,
+        wait_policy: WaitPolicy,
+        hook_errors: &HookErrors,
+        resizes: &Resizes,
+        post_starts: &PostStarts,
+    ) -> PodTask {
         match self {
             Self::CreatePod(config) => {
                 let ctor = move || {
@@ -93,14 +265,14 @@ impl crate::state::PodStep {
                     let config = config.clone();
                     async move { rsc.create_sandbox(config).await }
                 };
-                let task = Task::spawn(ctor, RestartPolicy::Always, CRI_RETRY_INTERVAL_MS);
+                let task = Task::spawn(ctor, RestartPolicy::Always, CRI_BACKOFF, finished.clone());
                 PodTask::CreatePod(task)
             }
             Self::ChangePod(names) => {
                 let mut tasks = HashMap::new();
                 for (name, step) in names {
                     let rsc = rsc.clone();
-                    tasks.insert(name, step.spawn(rsc));
+                    tasks.insert(name, step.spawn(rsc, finished, wait_policy, hook_errors, resizes, post_starts));
                 }
                 PodTask::ChangePod(tasks)
             }
@@ -110,7 +282,7 @@ impl crate::state::PodStep {
                     let pod_id = pod_id.clone();
                     async move { rsc.remove_pod(pod_id).await }
                 };
-                let task = Task::spawn(ctor, RestartPolicy::Always, CRI_RETRY_INTERVAL_MS);
+                let task = Task::spawn(ctor, RestartPolicy::Always, CRI_BACKOFF, finished.clone());
                 PodTask::DeletePod(task)
             }
         }
@@ -118,56 +290,271 @@ impl crate::state::PodStep {
 }
 
 impl crate::state::ContainerStep {
-    fn spawn(self, rsc: RuntimeClient) -> ContainerTask {
+    fn spawn(
+        self,
+        rsc: RuntimeClient,
+        finished: &Arc<Notify>,
+        wait_policy: WaitPolicy,
+        hook_errors: &HookErrors,
+        resizes: &Resizes,
+        post_starts: &PostStarts,
+    ) -> ContainerTask {
         match self {
-            Self::CreateCtr(pod_id, container_config, sandbox_config) => {
+            Self::CreateCtr(pod_id, container_config, sandbox_config, env_values) => {
                 let ctor = move || { 
                     let pod_id = pod_id.clone();
                     let container_config = container_config.clone();
                     let sandbox_config = sandbox_config.clone();
+                    let env_values = env_values.clone();
                     let mut rsc = rsc.clone();
                     async move {
                        rsc.pull_image(container_config.image.clone()).await?;
-                       rsc.create_container(pod_id, container_config, sandbox_config).await
+                       rsc.create_container(pod_id, container_config, sandbox_config, env_values).await
                     }
                 };
-                let task = Task::spawn(ctor, RestartPolicy::Always, CRI_RETRY_INTERVAL_MS);
+                let task = Task::spawn(ctor, RestartPolicy::Always, CREATE_CTR_BACKOFF, finished.clone());
                 ContainerTask::CreateCtr(task)
             }
-            Self::StartCtr(id) => {
+            Self::StartCtr(id, post_start) => {
+                let hook_errors = hook_errors.clone();
+                let post_starts = post_starts.clone();
                 let ctor = move || {
                     let id = id.clone();
                     let mut rsc = rsc.clone();
-                    async move { rsc.start_container(id).await }
+                    let post_start = post_start.clone();
+                    let hook_errors = hook_errors.clone();
+                    let post_starts = post_starts.clone();
+                    async move {
+                        let hook = match post_start {
+                            Some(hook) => hook,
+                            None => { return rsc.start_container(id).await; }
+                        };
+                        // From before it's running, so that it's never seen running without its hook.
+                        post_starts.lock().unwrap().insert(id.clone());
+                        let result = async {
+                            rsc.start_container(id.clone()).await?;
+                            if let Err(e) = run_check(&mut rsc, &id, &hook.into(), POST_START_TIMEOUT).await {
+                                // Like a crash: the container is restarted according to its pod's restart policy.
+                                println!("PostStart hook for container {} failed, killing it: {}", id, e);
+                                hook_errors.lock().unwrap().insert(id.clone(), format!("PostStart hook failed: {}", e));
+                                rsc.stop_container(id.clone(), 0).await?;
+                            }
+                            Ok::<_, tonic::Status>(())
+                        }.await;
+                        post_starts.lock().unwrap().remove(&id);
+                        result
+                    }
                 };
-                let task = Task::spawn(ctor, RestartPolicy::Always, CRI_RETRY_INTERVAL_MS);
+                // Once the container is running there's no step for it any more, and a re-plan
+                // mustn't cut its PostStart hook short.
+                let task = Task::spawn_draining(ctor, RestartPolicy::Always, CRI_BACKOFF, finished.clone());
                 ContainerTask::StartCtr(task)
             },
-            Self::StopCtr(id) => {
+            Self::StopCtr(id, stop) => {
+                let hook_errors = hook_errors.clone();
                 let ctor = move || {
                     let mut rsc = rsc.clone();
                     let id = id.clone();
-                    async move { rsc.stop_container(id).await }
+                    let stop = stop.clone();
+                    let hook_errors = hook_errors.clone();
+                    async move { stop_gracefully(&mut rsc, id, &stop, &hook_errors).await }
                 };
-                let task = Task::spawn(ctor, RestartPolicy::Always, CRI_RETRY_INTERVAL_MS);
+                // A re-plan mustn't cut the grace period short.
+                let task = Task::spawn_draining(ctor, RestartPolicy::Always, CRI_BACKOFF, finished.clone());
                 ContainerTask::StopCtr(task)
             },
+            Self::UpdateResources(id, resources) => {
+                let resizes = resizes.clone();
+                let wanted = resources.clone();
+                let ctor = move || {
+                    let id = id.clone();
+                    let resources = resources.clone();
+                    let resizes = resizes.clone();
+                    let mut rsc = rsc.clone();
+                    async move {
+                        use tonic::Code;
+                        let resized = match rsc.update_container_resources(id.clone(), &resources).await {
+                            Ok(()) => Some(resources),
+                            // Only a refusal means it has to be recreated. Anything else is worth another try.
+                            Err(e) if matches!(e.code(), Code::InvalidArgument | Code::Unimplemented | Code::FailedPrecondition) => {
+                                println!("Could not update the resources of container {}, recreating it instead: {}", id, e.message());
+                                None
+                            }
+                            // Gone already, which the next refresh will show.
+                            Err(e) if e.code() == Code::NotFound => { return Ok(()); }
+                            Err(e) => { return Err(e); }
+                        };
+                        resizes.lock().unwrap().insert(id, resized);
+                        Ok(())
+                    }
+                };
+                let task = Task::spawn(ctor, RestartPolicy::Always, CRI_BACKOFF, finished.clone());
+                ContainerTask::UpdateResources(task, wanted)
+            },
             Self::DeleteCtr(id) => {
                 let ctor = move || {
                     let id = id.clone();
                     let mut rsc = rsc.clone();
                     async move { rsc.remove_container(id).await }
                 };
-                let task = Task::spawn(ctor, RestartPolicy::Always, CRI_RETRY_INTERVAL_MS);
+                let task = Task::spawn(ctor, RestartPolicy::Always, CRI_BACKOFF, finished.clone());
                 ContainerTask::DeleteCtr(task)
             },
-            Self::WaitCtr(_) => {
-                let ctor = || {
-                    async move { Ok::<(),tonic::Status>(()) }
+            Self::WaitCtr(id) => {
+                // The deadline holds across retries.
+                let deadline = tokio::time::Instant::now() + wait_policy.deadline;
+                let ctor = move || {
+                    let id = id.clone();
+                    let mut rsc = rsc.clone();
+                    async move { wait_for_container(&mut rsc, id, deadline, wait_policy).await }
                 };
-                let task = Task::spawn(ctor, RestartPolicy::Never, CRI_RETRY_INTERVAL_MS);
+                let task = Task::spawn(ctor, RestartPolicy::Always, CRI_BACKOFF, finished.clone());
                 ContainerTask::WaitCtr(task)
             }
         }
     }
-}
\ No newline at end of file
+}
+
+/// Wait for a container to leave the Unknown state, and force it out if it doesn't by the deadline.
+async fn wait_for_container(
+    rsc: &mut RuntimeClient,
+    id: CtrId,
+    deadline: tokio::time::Instant,
+    policy: WaitPolicy,
+) -> Result<(), tonic::Status> {
+    let mut warned = false;
+    loop {
+        match rsc.container_status(id.clone()).await {
+            Ok(status) if to_state(status.state) != cri::ContainerState::ContainerUnknown => { return Ok(()); }
+            Ok(_) => {}
+            Err(e) if e.code() == tonic::Code::NotFound => { return Ok(()); }
+            Err(e) => { return Err(e); }
+        }
+        if tokio::time::Instant::now() >= deadline {
+            if policy.escalate { break; }
+            if !warned {
+                println!("Container {} is still in an unknown state after {:?}. Waiting.", id, policy.deadline);
+                warned = true;
+            }
+        }
+        tokio::time::sleep(policy.poll_interval).await;
+    }
+    println!("Container {} is still in an unknown state after {:?}. Stopping and removing it.", id, policy.deadline);
+    rsc.stop_container(id.clone(), 0).await?;
+    rsc.remove_container(id).await
+}
+
+fn signal_number(name: &str) -> Option<libc::c_int> {
+    Some(match name {
+        "HUP" => libc::SIGHUP,
+        "INT" => libc::SIGINT,
+        "QUIT" => libc::SIGQUIT,
+        "KILL" => libc::SIGKILL,
+        "USR1" => libc::SIGUSR1,
+        "USR2" => libc::SIGUSR2,
+        "PIPE" => libc::SIGPIPE,
+        "ALRM" => libc::SIGALRM,
+        "TERM" => libc::SIGTERM,
+        "CONT" => libc::SIGCONT,
+        "STOP" => libc::SIGSTOP,
+        "TSTP" => libc::SIGTSTP,
+        "WINCH" => libc::SIGWINCH,
+        "PWR" => libc::SIGPWR,
+        _ => { return None; }
+    })
+}
+
+/// Send a signal to a container's main process from the host. Returns false if it couldn't be, e.g.
+/// because the agent isn't in the host's pid namespace.
+async fn signal_from_host(rsc: &mut RuntimeClient, id: &CtrId, signal: &str) -> bool {
+    let (Some(signum), Ok(Some(pid))) = (signal_number(signal), rsc.container_pid(id.clone()).await) else {
+        return false;
+    };
+    // Make sure the pid is the container's in our namespace, and hasn't been reused since.
+    let in_container = std::fs::read_to_string(format!("/proc/{}/cgroup", pid))
+        .map_or(false, |cgroup| cgroup.contains(id.as_str()));
+    in_container && unsafe { libc::kill(pid, signum) } == 0
+}
+
+/// Ask a container to stop, and kill it if it hasn't by the end of its grace period.
+/// Its PreStop hook, if any, runs first and counts against the grace period.
+async fn stop_gracefully(rsc: &mut RuntimeClient, id: CtrId, stop: &StopOptions, hook_errors: &HookErrors)
+    -> Result<(), tonic::Status>
+{
+    let mut stop = stop.clone();
+    if let Some(ref hook) = stop.pre_stop {
+        let start = tokio::time::Instant::now();
+        if let Err(e) = run_check(rsc, &id, &hook.clone().into(), stop.grace_period).await {
+            println!("PreStop hook for container {} failed, stopping it anyway: {}", id, e);
+            hook_errors.lock().unwrap().insert(id.clone(), format!("PreStop hook failed: {}", e));
+        }
+        stop.grace_period = stop.grace_period.saturating_sub(start.elapsed());
+    }
+    let grace_period = stop.grace_period.as_secs() as i64;
+    let signal = match &stop.signal {
+        // The CRI only sends the image's stop signal, so send ours ourselves.
+        Some(signal) => signal.trim_start_matches("SIG"),
+        None => { return rsc.stop_container(id, grace_period).await; }
+    };
+    if !signal_from_host(rsc, &id, signal).await {
+        // Not every image has a kill to exec, e.g. distroless ones.
+        let kill = vec!["kill".to_owned(), "-s".to_owned(), signal.to_owned(), "1".to_owned()];
+        let error = match rsc.exec_sync(id.clone(), kill, grace_period).await {
+            Ok(resp) if resp.exit_code == 0 => None,
+            Ok(resp) => Some(String::from_utf8_lossy(&resp.stderr).trim().to_owned()),
+            Err(e) => Some(e.message().to_owned()),
+        };
+        if let Some(e) = error {
+            println!("Couldn't send SIG{} to container {}, stopping it with the image's stop signal: {}", signal, id, e);
+            hook_errors.lock().unwrap().insert(id.clone(), format!("Stop signal SIG{} could not be sent: {}", signal, e));
+            return rsc.stop_container(id, grace_period).await;
+        }
+    }
+    let deadline = tokio::time::Instant::now() + stop.grace_period;
+    while tokio::time::Instant::now() < deadline {
+        match rsc.container_status(id.clone()).await {
+            Ok(status) if to_state(status.state) == cri::ContainerState::ContainerExited => { return Ok(()); }
+            Err(e) if e.code() == tonic::Code::NotFound => { return Ok(()); }
+            _ => {}
+        }
+        tokio::time::sleep(STOP_POLL_INTERVAL).await;
+    }
+    rsc.stop_container(id, 0).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wait_policy_from_str() {
+        let policy: WaitPolicy = "90".parse().unwrap();
+        assert_eq!((policy.deadline, policy.escalate), (Duration::from_secs(90), true));
+        let policy: WaitPolicy = "never".parse().unwrap();
+        assert!(!policy.escalate);
+        assert!("soon".parse::<WaitPolicy>().is_err());
+    }
+
+    #[test]
+    fn signal_names_map_to_numbers() {
+        assert_eq!(signal_number("QUIT"), Some(libc::SIGQUIT));
+        assert_eq!(signal_number("WINCH"), Some(libc::SIGWINCH));
+        assert_eq!(signal_number("RTMIN"), None);
+    }
+
+    #[test]
+    fn hook_errors_go_with_their_containers() {
+        use crate::state::tests::{container, new_state, sandbox};
+        let worktree = WorkTree::new(WaitPolicy::default());
+        worktree.hook_errors.lock().unwrap().insert("c1".to_owned(), "PreStop hook failed".to_owned());
+        worktree.hook_errors.lock().unwrap().insert("c2".to_owned(), "PostStart hook failed".to_owned());
+        let mut state = new_state(UnmanagedPolicy::Ignore);
+        state.ingest(
+            vec![container("c1", "p1", "app", cri::ContainerState::ContainerRunning, 0)],
+            vec![sandbox("p1", "uid-1")],
+        );
+        worktree.prune_hook_errors(&state);
+        assert_eq!(worktree.hook_error(&"c1".to_owned()).as_deref(), Some("PreStop hook failed"));
+        assert_eq!(worktree.hook_error(&"c2".to_owned()), None);
+    }
+}